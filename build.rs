@@ -0,0 +1,17 @@
+use std::process::Command;
+
+/// Exposes the current git commit as `BRICKROLL_GIT_SHA` when building from a
+/// git checkout.
+fn main() {
+    let sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok());
+    if let Some(sha) = sha {
+        println!("cargo:rustc-env=BRICKROLL_GIT_SHA={}", sha.trim());
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,442 @@
+use crate::compiler::CompilerError;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BfInstruction {
+    IncPointer,
+    DecPointer,
+    IncData,
+    DecData,
+    Output,
+    Input,
+    LoopStart,
+    LoopEnd,
+    /// Adds a (possibly negative) amount to the current cell
+    AddData(i32),
+    /// Sets the current cell to zero
+    ClearData,
+    /// Halts with an error unless the current cell holds the given value.
+    /// Written as a `; assert cell == N` comment at the end of a line.
+    AssertData(i32),
+    /// Waits for as long as the current cell says, in units chosen by the
+    /// runtime. Only parsed with the sleep extension, and written as `*`.
+    Sleep,
+}
+
+impl fmt::Display for BfInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IncPointer => write!(f, ">"),
+            Self::DecPointer => write!(f, "<"),
+            Self::IncData => write!(f, "+"),
+            Self::DecData => write!(f, "-"),
+            Self::Output => write!(f, "."),
+            Self::Input => write!(f, ","),
+            Self::LoopStart => write!(f, "["),
+            Self::LoopEnd => write!(f, "]"),
+            Self::AddData(n) => {
+                let c = if *n < 0 { '-' } else { '+' };
+                for _ in 0..n.unsigned_abs() {
+                    write!(f, "{}", c)?;
+                }
+                Ok(())
+            }
+            Self::ClearData => write!(f, "[-]"),
+            Self::AssertData(n) => writeln!(f, "; assert cell == {}", n),
+            Self::Sleep => write!(f, "*"),
+        }
+    }
+}
+
+/// Language a Brainfuck program is written in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BfDialect {
+    /// Brainfuck itself, with `; assert` comments and the sleep extension
+    #[default]
+    Standard,
+    /// Ook!, which spells each command as a pair of `Ook.`, `Ook?` and
+    /// `Ook!` words
+    Ook,
+    /// Ook! with the words spelled as a space, a tab and a newline, so that
+    /// each command is a pair of these characters and everything else is a
+    /// comment
+    Whitespace,
+    /// Brainfuck with each command spelled as a lowercase word: `right`,
+    /// `left`, `inc`, `dec`, `out`, `in`, `loop` and `end`. Other words and
+    /// everything between words are comments.
+    Brainfuck2,
+}
+
+/// Reads the instructions of a dialect one at a time
+pub trait Tokenizer {
+    /// Removes the next instruction, together with any comment before it,
+    /// from the start of `src` and returns it, or returns `None` once no
+    /// instructions are left
+    fn next_instruction(src: &mut &str) -> Option<BfInstruction>;
+}
+
+pub struct OokTokenizer;
+
+impl OokTokenizer {
+    /// The punctuation of the next `Ook` word, skipping everything before it
+    fn next_word(src: &mut &str) -> Option<char> {
+        loop {
+            let rest = &src[src.find("Ook")? + "Ook".len()..];
+            match rest.chars().next() {
+                Some(c @ ('.' | '?' | '!')) => {
+                    *src = &rest[1..];
+                    return Some(c);
+                }
+                _ => *src = rest,
+            }
+        }
+    }
+}
+
+impl Tokenizer for OokTokenizer {
+    fn next_instruction(src: &mut &str) -> Option<BfInstruction> {
+        loop {
+            if let Some(instr) = ook_instruction(Self::next_word(src)?, Self::next_word(src)?) {
+                return Some(instr);
+            }
+        }
+    }
+}
+
+pub struct WhitespaceTokenizer;
+
+impl WhitespaceTokenizer {
+    /// The punctuation of the Ook! word that the next space, tab or newline
+    /// stands for, skipping everything before it
+    fn next_word(src: &mut &str) -> Option<char> {
+        let idx = src.find([' ', '\t', '\n'])?;
+        let word = match src.as_bytes()[idx] {
+            b' ' => '.',
+            b'\t' => '?',
+            _ => '!',
+        };
+        *src = &src[idx + 1..];
+        Some(word)
+    }
+}
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn next_instruction(src: &mut &str) -> Option<BfInstruction> {
+        loop {
+            if let Some(instr) = ook_instruction(Self::next_word(src)?, Self::next_word(src)?) {
+                return Some(instr);
+            }
+        }
+    }
+}
+
+pub struct Brainfuck2Tokenizer;
+
+impl Tokenizer for Brainfuck2Tokenizer {
+    fn next_instruction(src: &mut &str) -> Option<BfInstruction> {
+        loop {
+            let rest = &src[src.find(|c: char| c.is_ascii_alphanumeric())?..];
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            *src = &rest[len..];
+            let instr = match &rest[..len] {
+                "right" => BfInstruction::IncPointer,
+                "left" => BfInstruction::DecPointer,
+                "inc" => BfInstruction::IncData,
+                "dec" => BfInstruction::DecData,
+                "out" => BfInstruction::Output,
+                "in" => BfInstruction::Input,
+                "loop" => BfInstruction::LoopStart,
+                "end" => BfInstruction::LoopEnd,
+                _ => continue,
+            };
+            return Some(instr);
+        }
+    }
+}
+
+/// The command spelled by a pair of Ook! words, given by their punctuation
+fn ook_instruction(first: char, second: char) -> Option<BfInstruction> {
+    let instr = match (first, second) {
+        ('.', '?') => BfInstruction::IncPointer,
+        ('?', '.') => BfInstruction::DecPointer,
+        ('.', '.') => BfInstruction::IncData,
+        ('!', '!') => BfInstruction::DecData,
+        ('!', '.') => BfInstruction::Output,
+        ('.', '!') => BfInstruction::Input,
+        ('!', '?') => BfInstruction::LoopStart,
+        ('?', '!') => BfInstruction::LoopEnd,
+        // `Ook? Ook?` has no meaning
+        _ => return None,
+    };
+    Some(instr)
+}
+
+pub struct BfParser;
+
+impl BfParser {
+    /// Parses a Brainfuck program, rejecting it if its brackets are unbalanced.
+    pub fn parse(program: &str) -> Result<Vec<BfInstruction>, CompilerError> {
+        let instrs = Self::instructions(program);
+        Self::check_balance(&instrs)?;
+        Ok(instrs)
+    }
+    pub fn check_balance(instrs: &[BfInstruction]) -> Result<(), CompilerError> {
+        let mut open = Vec::new();
+        for (i, instr) in instrs.iter().enumerate() {
+            match instr {
+                BfInstruction::LoopStart => open.push(i),
+                BfInstruction::LoopEnd => {
+                    open.pop()
+                        .ok_or(CompilerError::UnbalancedBrackets { position: i })?;
+                }
+                _ => {}
+            }
+        }
+        match open.pop() {
+            Some(position) => Err(CompilerError::UnbalancedBrackets { position }),
+            None => Ok(()),
+        }
+    }
+    /// Maps each Brainfuck character to its instruction, ignoring everything
+    /// else, and each assertion comment to `AssertData`. Bracket balance is
+    /// not checked.
+    pub fn instructions(program: &str) -> Vec<BfInstruction> {
+        Self::instructions_with_sleep(program, None)
+    }
+    /// `instructions`, which also maps `sleep_char` to `Sleep`
+    pub fn instructions_with_sleep(program: &str, sleep_char: Option<char>) -> Vec<BfInstruction> {
+        Self::instructions_with_offsets(program, sleep_char, None)
+            .into_iter()
+            .map(|(_, instr)| instr)
+            .collect()
+    }
+    /// The instructions of a program in `dialect`, with their byte offsets
+    /// as in `instructions_with_offsets`. Offsets in other dialects are
+    /// where the search for the instruction started. `comment_char` is only
+    /// used by standard Brainfuck.
+    pub fn dialect_instructions_with_offsets(
+        program: &str,
+        dialect: BfDialect,
+        sleep_char: Option<char>,
+        comment_char: Option<char>,
+    ) -> Vec<(usize, BfInstruction)> {
+        match dialect {
+            BfDialect::Standard => {
+                Self::instructions_with_offsets(program, sleep_char, comment_char)
+            }
+            BfDialect::Ook => tokenize::<OokTokenizer>(program),
+            BfDialect::Whitespace => tokenize::<WhitespaceTokenizer>(program),
+            BfDialect::Brainfuck2 => tokenize::<Brainfuck2Tokenizer>(program),
+        }
+    }
+    /// `instructions_with_sleep`, with the byte offset in `program` of the
+    /// character each instruction comes from (the `;` for assertions). With
+    /// `comment_char`, everything from that character to the end of the line
+    /// is ignored, except for an assertion starting at the comment.
+    pub fn instructions_with_offsets(
+        program: &str,
+        sleep_char: Option<char>,
+        comment_char: Option<char>,
+    ) -> Vec<(usize, BfInstruction)> {
+        let mut res = Vec::new();
+        let code_start = skip_shebang(program);
+        let mut offset = program.len() - code_start.len();
+        for line in code_start.split_inclusive('\n') {
+            let (mut code, mut assertion) = split_assertion(line);
+            if let Some(idx) = comment_char.and_then(|c| code.find(c)) {
+                // an assertion after the comment character is part of the comment
+                code = &code[..idx];
+                assertion = None;
+            }
+            res.extend(code.char_indices().filter_map(|(i, c)| {
+                let instr = match c {
+                    '>' => BfInstruction::IncPointer,
+                    '<' => BfInstruction::DecPointer,
+                    '+' => BfInstruction::IncData,
+                    '-' => BfInstruction::DecData,
+                    '.' => BfInstruction::Output,
+                    ',' => BfInstruction::Input,
+                    '[' => BfInstruction::LoopStart,
+                    ']' => BfInstruction::LoopEnd,
+                    _ if Some(c) == sleep_char => BfInstruction::Sleep,
+                    _ => return None,
+                };
+                Some((offset + i, instr))
+            }));
+            res.extend(assertion.map(|n| (offset + code.len(), BfInstruction::AssertData(n))));
+            offset += line.len();
+        }
+        res
+    }
+}
+
+fn tokenize<T: Tokenizer>(program: &str) -> Vec<(usize, BfInstruction)> {
+    let mut res = Vec::new();
+    let mut src = program;
+    loop {
+        let offset = program.len() - src.len();
+        match T::next_instruction(&mut src) {
+            Some(instr) => res.push((offset, instr)),
+            None => return res,
+        }
+    }
+}
+
+/// Splits a trailing `; assert cell == N` comment off a line. The comment is
+/// removed even where assertions are not compiled, so that a negative `N`
+/// is never read as `-`.
+fn split_assertion(line: &str) -> (&str, Option<i32>) {
+    if let Some(idx) = line.find(';') {
+        let value = line[idx + 1..]
+            .trim_start()
+            .strip_prefix("assert")
+            .and_then(|rest| rest.trim_start().strip_prefix("cell"))
+            .and_then(|rest| rest.trim_start().strip_prefix("=="))
+            .and_then(|rest| rest.trim().parse::<i32>().ok());
+        if value.is_some() {
+            return (&line[..idx], value);
+        }
+    }
+    (line, None)
+}
+
+/// Drops a leading `#!` interpreter line, which may contain characters such as
+/// `-` that would otherwise be compiled as instructions.
+fn skip_shebang(program: &str) -> &str {
+    if program.starts_with("#!") {
+        program.split_once('\n').map_or("", |(_, rest)| rest)
+    } else {
+        program
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shebang_line_is_not_compiled() {
+        let instrs = BfParser::instructions("#!/usr/bin/env bf-run --no-wrap.\n+.");
+        assert_eq!(instrs, [BfInstruction::IncData, BfInstruction::Output]);
+    }
+
+    #[test]
+    fn shebang_keeps_offsets_in_the_source() {
+        let instrs = BfParser::instructions_with_offsets("#!bf -\n+", None, None);
+        assert_eq!(instrs, [(7, BfInstruction::IncData)]);
+    }
+
+    #[test]
+    fn source_with_shebang_compiles_like_one_without() {
+        let code = "++[>+<-]>.\n";
+        assert_eq!(
+            BfParser::instructions(&format!("#!/usr/bin/env bf\n{}", code)),
+            BfParser::instructions(code)
+        );
+    }
+
+    #[test]
+    fn source_without_shebang_is_unchanged() {
+        // only `#!` at the very start is a shebang
+        assert_eq!(
+            BfParser::instructions("# - comment\n+"),
+            [BfInstruction::DecData, BfInstruction::IncData]
+        );
+        assert_eq!(
+            BfParser::instructions(" #!-\n+"),
+            [BfInstruction::DecData, BfInstruction::IncData]
+        );
+    }
+
+    #[test]
+    fn tabs_and_carriage_returns_are_ignored() {
+        assert_eq!(
+            BfParser::instructions("\t+\t.\r\n-\r"),
+            [
+                BfInstruction::IncData,
+                BfInstruction::Output,
+                BfInstruction::DecData
+            ]
+        );
+    }
+
+    #[test]
+    fn assertion_before_crlf_is_parsed() {
+        assert_eq!(
+            BfParser::instructions("+\t; assert cell == 1\r\n."),
+            [
+                BfInstruction::IncData,
+                BfInstruction::AssertData(1),
+                BfInstruction::Output
+            ]
+        );
+    }
+
+    #[test]
+    fn ook_word_pairs_are_commands() {
+        // `Ook? Ook?` is skipped, and so are words without punctuation
+        let program = "Ook. Ook? Ook. Ook.\nOok? Ook? Ook Ook! Ook. Ook! Ook? Ook? Ook! Ook? Ook.";
+        let instrs: Vec<_> =
+            BfParser::dialect_instructions_with_offsets(program, BfDialect::Ook, None, None)
+                .into_iter()
+                .map(|(_, instr)| instr)
+                .collect();
+        assert_eq!(
+            instrs,
+            [
+                BfInstruction::IncPointer,
+                BfInstruction::IncData,
+                BfInstruction::Output,
+                BfInstruction::LoopStart,
+                BfInstruction::LoopEnd,
+                BfInstruction::DecPointer
+            ]
+        );
+    }
+
+    #[test]
+    fn brainfuck2_words_are_commands() {
+        // words are only commands on their own, so `inside` is a comment
+        let program = "inc inside loop:dec right\tIN out end left,in";
+        let instrs: Vec<_> =
+            BfParser::dialect_instructions_with_offsets(program, BfDialect::Brainfuck2, None, None)
+                .into_iter()
+                .map(|(_, instr)| instr)
+                .collect();
+        assert_eq!(
+            instrs,
+            [
+                BfInstruction::IncData,
+                BfInstruction::LoopStart,
+                BfInstruction::DecData,
+                BfInstruction::IncPointer,
+                BfInstruction::Output,
+                BfInstruction::LoopEnd,
+                BfInstruction::DecPointer,
+                BfInstruction::Input
+            ]
+        );
+    }
+
+    #[test]
+    fn whitespace_pairs_are_ook_words() {
+        // `Ook. Ook.`, `Ook! Ook.`, `Ook! Ook?`, `Ook? Ook!`, with comments
+        let program = "  x\n y\n\t\t\n-+[]";
+        let instrs: Vec<_> =
+            BfParser::dialect_instructions_with_offsets(program, BfDialect::Whitespace, None, None)
+                .into_iter()
+                .map(|(_, instr)| instr)
+                .collect();
+        assert_eq!(
+            instrs,
+            [
+                BfInstruction::IncData,
+                BfInstruction::Output,
+                BfInstruction::LoopStart,
+                BfInstruction::LoopEnd
+            ]
+        );
+    }
+}
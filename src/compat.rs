@@ -0,0 +1,70 @@
+use std::fmt;
+
+/// Part of Rickroll that not every interpreter supports
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Feature {
+    /// `ARRAY` values and the `Array*` builtins, which hold the tape
+    Arrays,
+    /// Conversion between characters and integers in the `CharToInt` and
+    /// `IntToChar` verses, needed by `,` and by `.` of unknown values
+    CharConversion,
+    /// The `PutChar` and `PutStr` builtins
+    Output,
+    /// The `ReadLine` builtin
+    Input,
+    /// Verses that no interpreter defines, such as `Sleep`, which the
+    /// runtime has to provide
+    RuntimeVerses,
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Arrays => "arrays",
+            Self::CharConversion => "character conversion",
+            Self::Output => "output",
+            Self::Input => "input",
+            Self::RuntimeVerses => "runtime-provided verses",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A Rickroll interpreter and the features it supports
+pub struct Interpreter {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub features: &'static [Feature],
+}
+
+/// Interpreters known to `--compat-check`
+pub const INTERPRETERS: &[Interpreter] = &[
+    Interpreter {
+        name: "rickroll",
+        description: "the reference interpreter",
+        features: &[
+            Feature::Arrays,
+            Feature::CharConversion,
+            Feature::Output,
+            Feature::Input,
+        ],
+    },
+    Interpreter {
+        name: "minimal",
+        description: "an interpreter with arrays but no characters or I/O",
+        features: &[Feature::Arrays],
+    },
+];
+
+impl Interpreter {
+    pub fn find(name: &str) -> Option<&'static Interpreter> {
+        INTERPRETERS.iter().find(|interp| interp.name == name)
+    }
+    /// The features in `required` that the interpreter does not support
+    pub fn missing(&self, required: impl IntoIterator<Item = Feature>) -> Vec<Feature> {
+        required
+            .into_iter()
+            .filter(|feature| !self.features.contains(feature))
+            .collect()
+    }
+}
@@ -1,12 +1,56 @@
+use crate::bf::{BfDialect, BfInstruction, BfParser};
+use crate::compat::Feature;
+use crate::diff;
+use crate::emulate::{self, Outcome};
+use crate::highlight::Highlighter;
+use crate::opt::{self, PassKind, PassPipeline};
+use crate::pretty;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+#[cfg(feature = "validate")]
+use regex::RegexSet;
+#[cfg(feature = "hash")]
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::env;
 use std::fmt::{self, Write};
+use std::fs;
+use std::io;
 use std::iter;
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+#[cfg(feature = "validate")]
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-enum Var {
+/// Ordered as declared here, which is the order `Compiler::normalize` sorts
+/// declarations and initializations into
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Var {
     Zero,
     Pointer,
     Tape,
     Temp,
     Buffer,
+    /// The cell a multiply loop counts down, see `Expr::MulAdd`
+    Count,
+    /// The character a loop prints from a cell that it never changes, read
+    /// once before the loop, see `invariant_output_cells`
+    Cached,
+    /// Local to the `PutInt` verse
+    Digit,
+    /// The cells before the current one, nearest first, with
+    /// `PointerType::Cursor`
+    Left,
+}
+
+impl Var {
+    /// Whether the variable only holds intermediate values, so that
+    /// `Compiler::reuse_scratch_vars` may let it share a variable with
+    /// another one
+    fn is_scratch(self) -> bool {
+        matches!(self, Self::Temp | Self::Count | Self::Digit)
+    }
 }
 
 impl fmt::Display for Var {
@@ -20,21 +64,48 @@ impl fmt::Display for Var {
                 Self::Temp => "Temp",
                 Self::Buffer => "Buffer",
                 Self::Zero => "Zero",
+                Self::Count => "Count",
+                Self::Cached => "Cached",
+                Self::Digit => "Digit",
+                Self::Left => "Left",
             }
         )
     }
 }
 
-enum Literal {
+/// A constant value in the generated program
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    /// A character, which must be printable or `\n`: no other escapes are
+    /// known to be understood, see `CompilerError::UnprintableLiteral`
     Char(char),
     Int(u8),
+    /// Only produced where a signed value is needed, such as the step of a
+    /// leftward `ArrayFindZero` or a negative factor of `Expr::MulAdd`;
+    /// never assigned to an index variable
+    NegativeInt(i8),
+    /// Text, with the same restriction on its characters as `Char`
+    Str(String),
     EmptyArray,
 }
 
+impl Literal {
+    /// Whether the literal holds a control character other than `\n`
+    fn has_unprintable_char(&self) -> bool {
+        let unprintable = |c: char| c.is_control() && c != '\n';
+        match self {
+            Self::Char(c) => unprintable(*c),
+            Self::Str(s) => s.chars().any(unprintable),
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for Literal {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Int(i) => write!(f, "{}", i),
+            Self::NegativeInt(i) => write!(f, "{}", i),
             Self::Char(c) => {
                 if *c == '\n' {
                     write!(f, "'\\n'")
@@ -46,19 +117,76 @@ impl fmt::Display for Literal {
                     write!(f, "'{}'", c)
                 }
             }
+            Self::Str(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '\n' => write!(f, "\\n")?,
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        _ => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
             Self::EmptyArray => write!(f, "ARRAY"),
         }
     }
 }
 
-enum Expr {
+/// An expression assigned, returned or tested by a command
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
     Inc(Var),
     Dec(Var),
+    Add(Var, Literal),
+    Sub(Var, Literal),
+    Div(Var, Literal),
+    Mod(Var, Literal),
+    /// Negated value of a variable. Signed values are only allowed in scratch
+    /// computations such as differences for bounds checks; tape cells must
+    /// never be assigned a negative value.
+    Neg(Var),
+    /// The first variable plus the second one times the literal, for folding
+    /// loops such as `[->+++<]` into a single statement
+    MulAdd(Var, Var, Literal),
     ArrayAccess(Var, Var),
     IsEqualLiteral(Var, Literal),
     IsEqualVar(Var, Var),
     IsNotEqualLiteral(Var, Literal),
+    IsLessVar(Var, Var),
+    IsGreaterEqualVar(Var, Var),
     Literal(Literal),
+    Var(Var),
+}
+
+impl Expr {
+    fn vars(&self) -> Vec<Var> {
+        match self {
+            Self::Inc(v) | Self::Dec(v) | Self::Neg(v) | Self::Var(v) => vec![*v],
+            Self::Add(v, _) | Self::Sub(v, _) | Self::Div(v, _) | Self::Mod(v, _) => vec![*v],
+            Self::IsEqualLiteral(v, _) | Self::IsNotEqualLiteral(v, _) => vec![*v],
+            Self::MulAdd(a, b, _)
+            | Self::ArrayAccess(a, b)
+            | Self::IsEqualVar(a, b)
+            | Self::IsLessVar(a, b)
+            | Self::IsGreaterEqualVar(a, b) => vec![*a, *b],
+            Self::Literal(_) => Vec::new(),
+        }
+    }
+    fn vars_mut(&mut self) -> Vec<&mut Var> {
+        match self {
+            Self::Inc(v) | Self::Dec(v) | Self::Neg(v) | Self::Var(v) => vec![v],
+            Self::Add(v, _) | Self::Sub(v, _) | Self::Div(v, _) | Self::Mod(v, _) => vec![v],
+            Self::IsEqualLiteral(v, _) | Self::IsNotEqualLiteral(v, _) => vec![v],
+            Self::MulAdd(a, b, _)
+            | Self::ArrayAccess(a, b)
+            | Self::IsEqualVar(a, b)
+            | Self::IsLessVar(a, b)
+            | Self::IsGreaterEqualVar(a, b) => vec![a, b],
+            Self::Literal(_) => Vec::new(),
+        }
+    }
 }
 
 impl fmt::Display for Expr {
@@ -66,70 +194,439 @@ impl fmt::Display for Expr {
         match self {
             Self::Inc(v) => write!(f, "{} + 1", v),
             Self::Dec(v) => write!(f, "{} - 1", v),
+            Self::Add(v, l) => write!(f, "{} + {}", v, l),
+            Self::Sub(v, l) => write!(f, "{} - {}", v, l),
+            Self::Div(v, l) => write!(f, "{} / {}", v, l),
+            Self::Mod(v, l) => write!(f, "{} % {}", v, l),
+            Self::Neg(v) => write!(f, "-{}", v),
+            Self::MulAdd(dest, src, k) => write!(f, "{} + {} * {}", dest, src, k),
             Self::ArrayAccess(array, idx) => write!(f, "{} : {}", array, idx),
             Self::IsEqualLiteral(v, l) => write!(f, "{} == {}", v, l),
             Self::IsNotEqualLiteral(v, l) => write!(f, "{} != {}", v, l),
             Self::IsEqualVar(v, v2) => write!(f, "{} == {}", v, v2),
+            Self::IsLessVar(v, v2) => write!(f, "{} < {}", v, v2),
+            Self::IsGreaterEqualVar(v, v2) => write!(f, "{} >= {}", v, v2),
             Self::Literal(l) => write!(f, "{}", l),
+            Self::Var(v) => write!(f, "{}", v),
         }
     }
 }
 
-enum Function {
+/// A verse or builtin the generated program calls or defines
+#[derive(Clone, Debug, PartialEq)]
+pub enum Function {
     ArrayReplace(Var, Var, Var),
     ArrayPush(Var, Var, Var),
     ArrayPop(Var, Var),
     ArrayLength(Var),
     CharToInt(Var),
     IntToChar(Var),
+    /// Prints the decimal representation of an integer
+    PutInt(Var),
     PutChar(Var),
+    PutStr(Var),
     ReadLine,
+    /// Not a Rickroll builtin; the runtime is expected to provide it
+    DebugPrint(Var),
+    /// Not a Rickroll builtin either, see `BfInstruction::Sleep`
+    Sleep(Var),
+    /// The verse with this number that `CompilerOptions::outline_loops`
+    /// extracted a loop into, which takes the tape and the pointer and
+    /// returns the tape
+    Loop(usize),
+    /// The verse with this number that `Compiler::split_chorus` moved part
+    /// of the chorus into, which takes the variables the chorus has
+    /// declared before it
+    Section(usize, Vec<Var>),
+    /// The first array with the elements of the second appended. Not a
+    /// Rickroll builtin; `define_array_concat` defines it as a verse, for
+    /// building `Buffer` from several sources of input.
+    ArrayConcat(Var, Var),
+    /// The index of the nearest element of the array that is zero, starting
+    /// at the index in the second variable and moving by the step (1 or -1)
+    /// in the third. Elements past the end count as zero, so the result is
+    /// the length of the array if there is no zero to the right, and -1 if
+    /// there is none to the left. Not a Rickroll builtin; the runtime is
+    /// expected to provide it.
+    ArrayFindZero(Var, Var, Var),
 }
 
 impl Function {
-    fn name(&self) -> &str {
-        match self {
+    pub fn name(&self) -> String {
+        let name = match self {
             Self::ArrayReplace(_, _, _) => "ArrayReplace",
             Self::ArrayPush(_, _, _) => "ArrayPush",
             Self::ArrayPop(_, _) => "ArrayPop",
             Self::CharToInt(_) => "CharToInt",
             Self::IntToChar(_) => "IntToChar",
+            Self::PutInt(_) => "PutInt",
             Self::PutChar(_) => "PutChar",
+            Self::PutStr(_) => "PutStr",
             Self::ArrayLength(_) => "ArrayLength",
             Self::ReadLine => "ReadLine",
+            Self::DebugPrint(_) => "DebugPrint",
+            Self::Sleep(_) => "Sleep",
+            Self::ArrayConcat(_, _) => "ArrayConcat",
+            Self::ArrayFindZero(_, _, _) => "ArrayFindZero",
+            Self::Loop(n) => return format!("Loop{}", n),
+            Self::Section(n, _) => return format!("Section{}", n),
+        };
+        name.to_owned()
+    }
+    pub fn vars(&self) -> Vec<Var> {
+        match self {
+            Self::ArrayReplace(a, b, c)
+            | Self::ArrayPush(a, b, c)
+            | Self::ArrayFindZero(a, b, c) => vec![*a, *b, *c],
+            Self::ArrayPop(a, b) | Self::ArrayConcat(a, b) => vec![*a, *b],
+            Self::CharToInt(v)
+            | Self::IntToChar(v)
+            | Self::PutInt(v)
+            | Self::PutChar(v)
+            | Self::PutStr(v)
+            | Self::ArrayLength(v)
+            | Self::DebugPrint(v)
+            | Self::Sleep(v) => vec![*v],
+            Self::ReadLine => Vec::new(),
+            Self::Loop(_) => vec![Var::Tape, Var::Pointer],
+            Self::Section(_, vars) => vars.clone(),
+        }
+    }
+    /// The variables passed explicitly, which `Loop` verses have none of
+    fn vars_mut(&mut self) -> Vec<&mut Var> {
+        match self {
+            Self::ArrayReplace(a, b, c)
+            | Self::ArrayPush(a, b, c)
+            | Self::ArrayFindZero(a, b, c) => vec![a, b, c],
+            Self::ArrayPop(a, b) | Self::ArrayConcat(a, b) => vec![a, b],
+            Self::CharToInt(v)
+            | Self::IntToChar(v)
+            | Self::PutInt(v)
+            | Self::PutChar(v)
+            | Self::PutStr(v)
+            | Self::ArrayLength(v)
+            | Self::DebugPrint(v)
+            | Self::Sleep(v) => vec![v],
+            Self::ReadLine | Self::Loop(_) => Vec::new(),
+            Self::Section(_, vars) => vars.iter_mut().collect(),
+        }
+    }
+    /// The feature an interpreter needs to run a call of this function
+    fn feature(&self) -> Feature {
+        match self {
+            Self::ArrayReplace(_, _, _)
+            | Self::ArrayPush(_, _, _)
+            | Self::ArrayPop(_, _)
+            | Self::ArrayLength(_)
+            | Self::ArrayConcat(_, _)
+            | Self::Loop(_)
+            | Self::Section(_, _) => Feature::Arrays,
+            Self::CharToInt(_) | Self::IntToChar(_) => Feature::CharConversion,
+            Self::PutInt(_) | Self::PutChar(_) | Self::PutStr(_) => Feature::Output,
+            Self::ReadLine => Feature::Input,
+            Self::DebugPrint(_) | Self::Sleep(_) | Self::ArrayFindZero(_, _, _) => {
+                Feature::RuntimeVerses
+            }
         }
     }
     fn args(&self) -> String {
         match self {
             Self::ArrayReplace(a, b, c) => format!("{}, {}, {}", a, b, c),
             Self::ArrayPush(a, b, c) => format!("{}, {}, {}", a, b, c),
+            Self::ArrayFindZero(a, b, c) => format!("{}, {}, {}", a, b, c),
             Self::ArrayPop(a, b) => format!("{}, {}", a, b),
+            Self::ArrayConcat(a, b) => format!("{}, {}", a, b),
             Self::CharToInt(v) => v.to_string(),
             Self::IntToChar(v) => v.to_string(),
+            Self::PutInt(v) => v.to_string(),
             Self::PutChar(v) => v.to_string(),
+            Self::PutStr(v) => v.to_string(),
             Self::ArrayLength(v) => v.to_string(),
             Self::ReadLine => "you".to_owned(),
+            Self::DebugPrint(v) => v.to_string(),
+            Self::Sleep(v) => v.to_string(),
+            Self::Loop(_) => format!("{}, {}", Var::Tape, Var::Pointer),
+            Self::Section(_, vars) => vars
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
         }
     }
 }
 
-enum Cmd {
+/// One statement of the generated program, which is emitted as one line,
+/// or two for declarations of verses and of a chorus with a parameter
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cmd {
     DeclareVar(Var),
     DeclareFn(Function),
     Return(Expr),
-    DeclareChorus,
+    /// The chorus, with its label and the parameter it takes, if any
+    DeclareChorus(Option<String>, Option<Var>),
     Assign(Var, Expr),
     Call(Function, Var),
     CallNoReturn(Function),
     StartCond(Expr),
     EndIf,
     EndWhile,
+    /// Ends the program by returning from the chorus
+    Halt,
+    /// Prints the position in the source program of the Brainfuck
+    /// instruction that the following commands implement
+    Trace(usize),
+    /// Emitted as a `#` line, with control characters escaped so that the
+    /// text can never spill onto a line of its own.
+    Comment(String),
+}
+
+impl Cmd {
+    /// The variables the command reads, writes or declares
+    fn vars(&self) -> Vec<Var> {
+        match self {
+            Self::DeclareVar(v) => vec![*v],
+            Self::DeclareFn(f) | Self::CallNoReturn(f) => f.vars(),
+            Self::Return(e) | Self::StartCond(e) => e.vars(),
+            Self::DeclareChorus(_, param) => param.iter().copied().collect(),
+            Self::Assign(v, e) => iter::once(*v).chain(e.vars()).collect(),
+            Self::Call(f, v) => iter::once(*v).chain(f.vars()).collect(),
+            Self::Halt => vec![Var::Zero],
+            Self::EndIf | Self::EndWhile | Self::Trace(_) | Self::Comment(_) => Vec::new(),
+        }
+    }
+    /// Replaces `from` with `to` wherever the command names it
+    fn rename_var(&mut self, from: Var, to: Var) {
+        let vars = match self {
+            Self::DeclareVar(v) => vec![v],
+            Self::DeclareFn(f) | Self::CallNoReturn(f) => f.vars_mut(),
+            Self::Return(e) | Self::StartCond(e) => e.vars_mut(),
+            Self::DeclareChorus(_, param) => param.iter_mut().collect(),
+            Self::Assign(v, e) => iter::once(v).chain(e.vars_mut()).collect(),
+            Self::Call(f, v) => iter::once(v).chain(f.vars_mut()).collect(),
+            Self::EndIf | Self::EndWhile | Self::Halt | Self::Trace(_) | Self::Comment(_) => {
+                Vec::new()
+            }
+        };
+        for v in vars {
+            if *v == from {
+                *v = to;
+            }
+        }
+    }
+    /// Whether the command gives `var` a new value without reading the old
+    /// one
+    fn overwrites(&self, var: Var) -> bool {
+        match self {
+            Self::Assign(v, e) => *v == var && !e.vars().contains(&var),
+            Self::Call(f, v) => *v == var && !f.vars().contains(&var),
+            _ => false,
+        }
+    }
+    /// Whether the command leaves every variable as it was, such as
+    /// `Never gonna give Temp Temp + 0`
+    fn is_noop(&self) -> bool {
+        match self {
+            Self::Assign(v, Expr::Var(w)) => v == w,
+            Self::Assign(v, Expr::Add(w, Literal::Int(0)) | Expr::Sub(w, Literal::Int(0))) => {
+                v == w
+            }
+            _ => false,
+        }
+    }
+    fn kind(&self) -> CmdKind {
+        match self {
+            Self::DeclareVar(_) => CmdKind::DeclareVar,
+            Self::DeclareFn(_) => CmdKind::DeclareFn,
+            Self::Return(_) => CmdKind::Return,
+            Self::DeclareChorus(_, _) => CmdKind::DeclareChorus,
+            Self::Assign(_, _) => CmdKind::Assign,
+            Self::Call(_, _) => CmdKind::Call,
+            Self::CallNoReturn(_) => CmdKind::CallNoReturn,
+            Self::StartCond(_) => CmdKind::StartCond,
+            Self::EndIf => CmdKind::EndIf,
+            Self::EndWhile => CmdKind::EndWhile,
+            Self::Halt => CmdKind::Halt,
+            Self::Trace(_) => CmdKind::Trace,
+            Self::Comment(_) => CmdKind::Comment,
+        }
+    }
+    fn emit(&self, res: &mut String, target: RickrollVersion) -> fmt::Result {
+        match target {
+            // Rickroll 2 does not change any syntax yet
+            RickrollVersion::V1 | RickrollVersion::V2 => self.emit_v1(res),
+        }
+    }
+    fn emit_v1(&self, res: &mut String) -> fmt::Result {
+        match self {
+            Self::DeclareVar(v) => writeln!(res, "Never gonna let {} down", v),
+            Self::DeclareFn(f) => {
+                writeln!(res, "[Verse {}]", f.name())?;
+                writeln!(res, "(Ooh give you {})", f.args())
+            }
+            Self::Return(e) => writeln!(
+                res,
+                "(Ooh) Never gonna give, never gonna give (give you {})",
+                e
+            ),
+            Self::DeclareChorus(label, param) => {
+                match label {
+                    Some(label) => writeln!(res, "[Chorus {}]", label)?,
+                    None => writeln!(res, "[Chorus]")?,
+                }
+                match param {
+                    Some(param) => writeln!(res, "(Ooh give you {})", param),
+                    None => Ok(()),
+                }
+            }
+            Self::Assign(v, e) => writeln!(res, "Never gonna give {} {}", v, e),
+            Self::Call(f, v) => {
+                write!(res, "(Ooh give you {}) ", v)?;
+                writeln!(res, "Never gonna run {} and desert {}", f.name(), f.args())
+            }
+            Self::CallNoReturn(f) => {
+                writeln!(res, "Never gonna run {} and desert {}", f.name(), f.args())
+            }
+            Self::StartCond(e) => writeln!(res, "Inside we both know {}", e),
+            Self::EndIf => writeln!(res, "Your heart's been aching but you're too shy to say it"),
+            Self::EndWhile => writeln!(res, "We know the game and we're gonna play it"),
+            Self::Trace(ln) => writeln!(res, "Never gonna say {}", ln),
+            Self::Halt => writeln!(
+                res,
+                "(Ooh) Never gonna give, never gonna give (give you {})",
+                Var::Zero
+            ),
+            Self::Comment(text) => {
+                write!(res, "#")?;
+                if !text.is_empty() {
+                    write!(res, " ")?;
+                }
+                for c in text.chars() {
+                    if c.is_control() {
+                        write!(res, "{}", c.escape_default())?;
+                    } else {
+                        write!(res, "{}", c)?;
+                    }
+                }
+                writeln!(res)
+            }
+        }
+    }
+}
+
+/// The kind of a command of the generated program, without its operands,
+/// see `Compiler::measure_output_size_distribution`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CmdKind {
+    DeclareVar,
+    /// The header of a verse
+    DeclareFn,
+    Return,
+    /// The header of the chorus
+    DeclareChorus,
+    Assign,
+    /// A call whose result is stored in a variable
+    Call,
+    CallNoReturn,
+    /// The start of an `if` or a `while`
+    StartCond,
+    EndIf,
+    EndWhile,
+    Halt,
+    Trace,
+    Comment,
+}
+
+/// One element of a pattern for [`Compiler::find_pattern`], which matches a
+/// single command
+#[derive(Clone, Debug, PartialEq)]
+pub enum CmdPattern {
+    /// Any assignment to a variable
+    AnyAssign,
+    /// Any call of a verse, whether or not its result is stored
+    AnyCall,
+    /// Exactly this command
+    SpecificCmd(Cmd),
+    /// Any command at all
+    Wildcard,
+}
+
+impl CmdPattern {
+    fn matches(&self, cmd: &Cmd) -> bool {
+        match self {
+            Self::AnyAssign => matches!(cmd, Cmd::Assign(_, _)),
+            Self::AnyCall => matches!(cmd, Cmd::Call(_, _) | Cmd::CallNoReturn(_)),
+            Self::SpecificCmd(expected) => cmd == expected,
+            Self::Wildcard => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RickrollVersion {
+    #[default]
+    V1,
+    V2,
+}
+
+/// How the generated program keeps track of the current cell
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PointerType {
+    /// `Pointer` holds the index of the current cell in `Tape`
+    #[default]
+    Index,
+    /// `Tape` starts at the current cell and `Left` holds the cells before
+    /// it, so that the current cell is always `Tape : Zero` and moving the
+    /// pointer moves a cell from one array to the other
+    Cursor,
+}
+
+/// Where the `CharToInt`/`IntToChar` verses are placed relative to the chorus
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    #[default]
+    VersesFirst,
+    ChorusFirst,
+}
+
+/// Number of tape cells to create before the program starts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TapePreallocate {
+    Cells(usize),
+    /// Enough cells for `Compiler::max_pointer_value` where it is known, and
+    /// `Compiler::tape_cell_count_lower_bound` otherwise
+    Auto,
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum CompilerError {
     FormatError(fmt::Error),
-    UnbalancedBrackets,
+    UnbalancedBrackets {
+        /// Index of the first unmatched bracket, counted in Brainfuck
+        /// instructions when parsing and in commands when checking generated
+        /// code. An unclosed bracket is reported at its innermost opening.
+        position: usize,
+    },
+    NegativeLiteral,
+    /// A character or string literal, such as the text of
+    /// `CompilerOptions::seed_input`, holds a control character other than
+    /// `\n`, which has no escape that Rickroll interpreters are known to
+    /// accept
+    UnprintableLiteral,
+    Io(io::Error),
+    /// The program is not valid base64, or does not decode to UTF-8 text
+    InvalidBase64,
+    /// The source contains no Brainfuck instructions at all, see
+    /// `CompilerOptions::allow_empty`
+    EmptyProgram,
+    /// `Compiler::trace_execution` found the program doing something that
+    /// has no defined meaning, such as indexing the tape out of its bounds
+    ExecutionError {
+        /// Position in the source program of the instruction that was
+        /// running, if the error happened after the preamble
+        position: Option<usize>,
+        message: String,
+    },
 }
 
 impl From<fmt::Error> for CompilerError {
@@ -138,207 +635,3271 @@ impl From<fmt::Error> for CompilerError {
     }
 }
 
-pub struct Compiler {
-    cmds: Vec<Cmd>,
+impl From<io::Error> for CompilerError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
 }
 
-impl Compiler {
-    pub fn read(program: &str) -> Compiler {
-        let mut compiler = Self { cmds: Vec::new() };
-        compiler.define_char_to_int();
-        compiler.define_int_to_char();
-        compiler.declare_chorus();
-        compiler.init_vars();
-        for c in program.chars() {
-            match c {
-                '>' => compiler.inc_pointer(),
-                '<' => compiler.dec_pointer(),
-                '+' => compiler.inc_data(),
-                '-' => compiler.dec_data(),
-                '.' => compiler.output_byte(),
-                ',' => compiler.read_byte(),
-                '[' => compiler.cond_jump(),
-                ']' => compiler.cond_jump_end(),
-                _ => {}
-            };
+#[derive(Clone)]
+pub struct CompilerOptions {
+    pub indent: i64,
+    /// Insert a trace statement before the commands of every Brainfuck
+    /// instruction, which prints the position of the instruction in the
+    /// source program. Positions do not depend on the optimization level.
+    pub trace: bool,
+    /// Emit the `CharToInt`/`IntToChar` verses, the chorus header and the
+    /// variable initialization. Without it, the output is only the chorus
+    /// body and the caller must provide compatible definitions.
+    pub preamble: bool,
+    pub chorus_label: Option<String>,
+    /// Append the Brainfuck program as comments after the generated code
+    pub embed_source: bool,
+    /// Precede the commands of each Brainfuck instruction with a comment
+    /// that explains what the instruction does and how it is compiled
+    pub explain: bool,
+    /// Optimization level used by `Compiler::from_optimized`
+    pub opt_level: u8,
+    /// Optimization passes to run, in order, instead of the ones selected
+    /// by `opt_level`
+    pub passes: Option<Vec<PassKind>>,
+    /// Only compile the Brainfuck commands with indices in `start..end`,
+    /// not counting comment characters
+    pub range: Option<(usize, usize)>,
+    /// Rickroll language version to generate
+    pub target: RickrollVersion,
+    /// Check the pointer before every tape access and halt with an error
+    /// message if it is out of bounds
+    pub check_bounds: bool,
+    /// Order of the verses and the chorus in the preamble
+    pub layout: Layout,
+    /// Pass the tape to a runtime-provided `DebugPrint` function at the start
+    /// of every loop iteration
+    pub debug_print_on_loop: bool,
+    /// Grow the tape to this many cells during variable initialization
+    /// instead of one at a time as the pointer first reaches them. Ignored
+    /// without the preamble.
+    pub tape_preallocate: Option<TapePreallocate>,
+    /// Input to read instead of stdin; once it is used up, `,` reads 0
+    pub seed_input: Option<String>,
+    /// Treat everything after the first `!` in the source as the program's
+    /// input, used instead of `seed_input`. Sources without a `!` read stdin
+    /// as usual.
+    pub split_on_bang: bool,
+    /// Make the chorus take the input as an array of characters in its
+    /// `Buffer` parameter instead of reading stdin, so that the program only
+    /// depends on its argument. `,` reads 0 after the end of the array.
+    /// Input from `seed_input` or `split_on_bang` is read before the array.
+    pub input_from_arg: bool,
+    /// Compile `; assert cell == N` comments into runtime checks
+    pub assertions: bool,
+    /// Tape index the pointer starts at. The tape is created with enough
+    /// cells for it. Ignored without the preamble.
+    pub pointer_start: usize,
+    /// Compile this character into a call of a runtime-provided `Sleep`
+    /// function with the current cell (the sleep extension). Without it,
+    /// the character is a comment like any other.
+    pub sleep_char: Option<char>,
+    /// Ignore everything from this character to the end of the line, so that
+    /// comments can contain Brainfuck characters. With `;`, assertions are
+    /// still recognized. Only used by standard Brainfuck.
+    pub comment_char: Option<char>,
+    /// Compile sources without any Brainfuck instructions, which are
+    /// otherwise rejected with `CompilerError::EmptyProgram` as they are
+    /// most likely a mistake
+    pub allow_empty: bool,
+    /// Experimental: compile loops that occur several times with the same
+    /// instructions into a verse each, which every occurrence calls. Only
+    /// loops of at least 8 instructions without input or assertions whose
+    /// body, and every loop in it, leaves the pointer where it started are
+    /// extracted. Ignored without the preamble, with `trace` and with
+    /// `check_bounds`.
+    pub outline_loops: bool,
+    /// Language the source is written in
+    pub dialect: BfDialect,
+    /// Values of the first cells of the tape when the program starts, see
+    /// `Compiler::set_tape_initial_values`. Optimizations that rely on the
+    /// tape starting out zero are skipped when it is not empty.
+    pub tape_init: Vec<u8>,
+    /// How the generated program keeps track of the current cell. Loops are
+    /// never outlined with `PointerType::Cursor`.
+    pub pointer_type: PointerType,
+    /// Compile `[>]` and `[<]` into a call of a runtime-provided
+    /// `ArrayFindZero` function instead of a loop. Ignored with `trace`,
+    /// `debug_print_on_loop` and `PointerType::Cursor`.
+    pub array_search: bool,
+    /// Move the end of the chorus into verses that it calls, so that the
+    /// chorus and each of them have at most this many lines where the
+    /// program allows it, see `Compiler::split_chorus`. Ignored without the
+    /// preamble.
+    pub max_chorus_lines: Option<usize>,
+    /// Make cells wrap around like bytes, so that `-` on a zero cell gives
+    /// 255 and `+` on 255 gives 0, with a check after every change of a
+    /// cell. Without it, cells hold any integer.
+    pub wrapping: bool,
+    /// With `wrapping`, leave out the checks and trust the program to keep
+    /// every cell between 0 and 255. Cells of a program that does not are
+    /// not wrapped around.
+    pub no_wrap_check: bool,
+}
+
+impl Default for CompilerOptions {
+    fn default() -> Self {
+        Self {
+            indent: 2,
+            trace: false,
+            preamble: true,
+            chorus_label: None,
+            embed_source: false,
+            explain: false,
+            opt_level: 0,
+            passes: None,
+            range: None,
+            target: RickrollVersion::V1,
+            check_bounds: false,
+            layout: Layout::VersesFirst,
+            debug_print_on_loop: false,
+            tape_preallocate: None,
+            seed_input: None,
+            split_on_bang: false,
+            input_from_arg: false,
+            assertions: false,
+            pointer_start: 0,
+            sleep_char: None,
+            comment_char: None,
+            allow_empty: false,
+            outline_loops: false,
+            dialect: BfDialect::Standard,
+            tape_init: Vec::new(),
+            pointer_type: PointerType::Index,
+            array_search: false,
+            max_chorus_lines: None,
+            wrapping: false,
+            no_wrap_check: false,
         }
-        compiler
     }
-    pub fn output(&self, indent: i64, trace: bool) -> Result<String, CompilerError> {
-        let mut res = String::new();
-        let mut level = 0i64;
-        let mut in_chorus = false;
-        for (ln, cmd) in self.cmds.iter().enumerate() {
-            match cmd {
-                Cmd::EndIf | Cmd::EndWhile => {
-                    if level == 0 {
-                        return Err(CompilerError::UnbalancedBrackets);
-                    } else {
-                        level -= 1;
-                    }
+}
+
+/// The warnings for `instrs`, which come from the source positions in
+/// `origins`
+fn find_warnings(instrs: &[BfInstruction], origins: &[usize]) -> Vec<Warning> {
+    let mut warnings: Vec<Warning> = instrs
+        .windows(2)
+        .zip(origins)
+        .filter(|(pair, _)| pair == &[BfInstruction::LoopStart, BfInstruction::LoopEnd])
+        .map(|(_, origin)| Warning {
+            kind: WarningKind::EmptyLoop,
+            position: Some(*origin),
+        })
+        .collect();
+    if !instrs.contains(&BfInstruction::Output) {
+        warnings.push(Warning {
+            kind: WarningKind::NoOutput,
+            position: None,
+        });
+    }
+    warnings
+}
+
+/// The number of nested counting loops in the loop starting at `start`,
+/// including itself, with the index of its end and the pointer offsets it
+/// writes to relative to its start. A counting loop leaves the pointer where
+/// it started and changes its cell by a fixed nonzero amount per iteration;
+/// a loop that clears its cell runs at most once and does not count. `None`
+/// if the loop, or a loop in it, is of neither kind.
+fn loop_degree(instrs: &[BfInstruction], start: usize) -> Option<(u32, usize, HashSet<i64>)> {
+    let mut offset = 0i64;
+    let mut delta = 0i64;
+    let mut clears = false;
+    let mut inner = 0;
+    let mut writes = HashSet::new();
+    let mut i = start + 1;
+    loop {
+        match instrs.get(i)? {
+            BfInstruction::IncPointer => offset += 1,
+            BfInstruction::DecPointer => offset -= 1,
+            BfInstruction::IncData | BfInstruction::DecData | BfInstruction::AddData(_) => {
+                if offset == 0 {
+                    delta += match instrs[i] {
+                        BfInstruction::IncData => 1,
+                        BfInstruction::DecData => -1,
+                        BfInstruction::AddData(n) => n as i64,
+                        _ => unreachable!("only additions reach here"),
+                    };
                 }
-                _ => {}
+                writes.insert(offset);
             }
-            if trace && in_chorus {
-                for _ in 0..level * indent {
-                    write!(res, " ")?;
+            BfInstruction::ClearData => {
+                if offset == 0 {
+                    clears = true;
+                    delta = 0;
                 }
-                writeln!(res, "Never gonna say {}", ln)?;
+                writes.insert(offset);
             }
-            for _ in 0..level * indent {
-                write!(res, " ")?;
+            BfInstruction::Input if offset == 0 => return None,
+            BfInstruction::Input => {
+                writes.insert(offset);
             }
-            match cmd {
-                Cmd::DeclareVar(v) => writeln!(res, "Never gonna let {} down", v)?,
-                Cmd::DeclareFn(f) => {
-                    writeln!(res, "[Verse {}]", f.name())?;
-                    writeln!(res, "(Ooh give you {})", f.args())?;
-                }
-                Cmd::Return(e) => writeln!(
-                    res,
-                    "(Ooh) Never gonna give, never gonna give (give you {})",
-                    e
-                )?,
-                Cmd::DeclareChorus => {
-                    writeln!(res, "[Chorus]")?;
-                    in_chorus = true
-                }
-                Cmd::Assign(v, e) => writeln!(res, "Never gonna give {} {}", v, e)?,
-                Cmd::Call(f, v) => {
-                    write!(res, "(Ooh give you {}) ", v)?;
-                    writeln!(res, "Never gonna run {} and desert {}", f.name(), f.args())?;
-                }
-                Cmd::CallNoReturn(f) => {
-                    writeln!(res, "Never gonna run {} and desert {}", f.name(), f.args())?
-                }
-                Cmd::StartCond(e) => {
-                    writeln!(res, "Inside we both know {}", e)?;
-                    level += 1;
+            BfInstruction::Output | BfInstruction::AssertData(_) | BfInstruction::Sleep => {}
+            BfInstruction::LoopStart => {
+                let (degree, end, inner_writes) = loop_degree(instrs, i)?;
+                // an inner loop that changes this loop's cell could make it
+                // run any number of times
+                if inner_writes.contains(&-offset) {
+                    return None;
                 }
-                Cmd::EndIf => {
-                    writeln!(res, "Your heart's been aching but you're too shy to say it")?;
+                writes.extend(inner_writes.iter().map(|w| w + offset));
+                inner = inner.max(degree);
+                i = end;
+            }
+            BfInstruction::LoopEnd => break,
+        }
+        i += 1;
+    }
+    let own = match (clears, delta) {
+        _ if offset != 0 => return None,
+        (true, 0) => 0,
+        // a cleared cell that is changed again never reaches zero
+        (true, _) | (false, 0) => return None,
+        (false, _) => 1,
+    };
+    Some((own + inner, i, writes))
+}
+
+/// Whether the body of the loop starting at `start` multiplies the cell of
+/// one of its inner loops by 2 or more, such as `[->[->>++<<]>>[-<<+>>]<<<]`
+/// doubling the second cell, so that each iteration does at least twice the
+/// work of the one before. Only multiply loops directly in the body move
+/// values between cells, and `[-]` and `,` reset them.
+fn doubles_inner_loop_cell(instrs: &[BfInstruction], start: usize) -> bool {
+    // the multiply loops of the body in order, with `None` for resets
+    let mut steps = Vec::new();
+    let mut offset = 0i64;
+    // nesting inside a loop of the body that is not a multiply loop
+    let mut depth = 0usize;
+    let mut i = start + 1;
+    while let Some(instr) = instrs.get(i) {
+        match instr {
+            BfInstruction::LoopStart if depth > 0 => depth += 1,
+            BfInstruction::LoopEnd if depth > 0 => depth -= 1,
+            _ if depth > 0 => {}
+            BfInstruction::IncPointer => offset += 1,
+            BfInstruction::DecPointer => offset -= 1,
+            BfInstruction::ClearData | BfInstruction::Input => steps.push((offset, None)),
+            BfInstruction::LoopStart => match multiply_loop(&instrs[i..]) {
+                Some((factors, len)) => {
+                    steps.push((offset, Some(factors)));
+                    i += len - 1;
                 }
-                Cmd::EndWhile => {
-                    writeln!(res, "We know the game and we're gonna play it")?;
+                None => depth = 1,
+            },
+            BfInstruction::LoopEnd => break,
+            _ => {}
+        }
+        i += 1;
+    }
+    steps.iter().any(|&(cell, ref factors)| {
+        if factors.is_none() {
+            return false;
+        }
+        // how many times the starting value of `cell` each cell holds
+        let mut gains = HashMap::from([(cell, 1i64)]);
+        for (control, factors) in &steps {
+            let gain = gains.remove(control).unwrap_or(0);
+            for (o, k) in factors.iter().flatten() {
+                *gains.entry(control + o).or_insert(0) += k * gain;
+            }
+        }
+        gains.get(&cell).is_some_and(|&gain| gain >= 2)
+    })
+}
+
+/// Minimum number of instructions, including the brackets, of a loop that
+/// `CompilerOptions::outline_loops` extracts into a verse
+const MIN_OUTLINED_LOOP_LEN: usize = 8;
+
+/// Largest number of iterations of a loop that `-O2` unrolls, see
+/// `Compiler::unroll_small_loops`
+const MAX_UNROLLED_ITERATIONS: usize = 8;
+
+/// Whether `cmds` only compute values and update arrays, which cannot
+/// print anything, end the program or fail, and never loop. Conditions must
+/// be closed within `cmds`. Returns `None` if they might, and otherwise
+/// whether `Pointer` is still a valid index of `Tape` afterwards, given
+/// `pointer_in_bounds` for before. Other indices must be 0 or, for
+/// `ArrayPush`, the length of the array (as when moving the pointer onto a
+/// new cell), since an index out of bounds would stop the program before it
+/// prints what it printed earlier.
+fn always_completes_silently(cmds: &[Cmd], mut pointer_in_bounds: bool) -> Option<bool> {
+    let mut depth = 0usize;
+    // variables holding the length of an array, with the array, as
+    // measured outside of conditions
+    let mut lengths: HashMap<Var, Var> = HashMap::new();
+    for cmd in cmds {
+        let assigned = match cmd {
+            Cmd::Comment(_) => continue,
+            Cmd::Assign(v, Expr::ArrayAccess(Var::Tape, Var::Pointer)) if pointer_in_bounds => *v,
+            Cmd::Call(Function::ArrayReplace(Var::Tape, Var::Pointer, _), Var::Tape)
+                if pointer_in_bounds =>
+            {
+                continue;
+            }
+            // moving right grows the tape, so only moving left can leave it
+            Cmd::Assign(
+                Var::Pointer,
+                Expr::Inc(Var::Pointer) | Expr::Add(Var::Pointer, Literal::Int(_)),
+            ) => continue,
+            Cmd::Assign(v, e)
+                if !matches!(e, Expr::ArrayAccess(..) | Expr::Div(..) | Expr::Mod(..)) =>
+            {
+                *v
+            }
+            Cmd::Call(Function::ArrayLength(array), v) => {
+                lengths.retain(|length, measured| length != v && measured != v);
+                if depth == 0 {
+                    lengths.insert(*v, *array);
                 }
+                continue;
             }
+            Cmd::Call(Function::ArrayPush(array, index, _), v)
+                if *index == Var::Zero || lengths.get(index) == Some(array) =>
+            {
+                *v
+            }
+            Cmd::StartCond(e) if !matches!(e, Expr::ArrayAccess(..)) => {
+                depth += 1;
+                continue;
+            }
+            // a StartCond closed by EndIf never runs more than once
+            Cmd::EndIf if depth > 0 => {
+                depth -= 1;
+                continue;
+            }
+            _ => return None,
+        };
+        lengths.retain(|length, measured| *length != assigned && *measured != assigned);
+        // pushing onto the tape keeps every index valid
+        if assigned == Var::Pointer
+            || assigned == Var::Tape && !matches!(cmd, Cmd::Call(Function::ArrayPush(..), _))
+        {
+            pointer_in_bounds = false;
         }
-        if level == 0 {
-            Ok(res)
-        } else {
-            Err(CompilerError::UnbalancedBrackets)
+    }
+    (depth == 0).then_some(pointer_in_bounds)
+}
+
+/// The direction of the scan loop (`[>]` or `[<]`) at the start of
+/// `instrs`, which `CompilerOptions::array_search` compiles into a call of
+/// `ArrayFindZero`: 1 to the right and -1 to the left
+fn scan_loop_step(instrs: &[BfInstruction]) -> Option<i64> {
+    match instrs {
+        [BfInstruction::LoopStart, BfInstruction::IncPointer, BfInstruction::LoopEnd, ..] => {
+            Some(1)
+        }
+        [BfInstruction::LoopStart, BfInstruction::DecPointer, BfInstruction::LoopEnd, ..] => {
+            Some(-1)
         }
+        _ => None,
     }
-    fn define_char_to_int(&mut self) {
-        self.cmds
-            .push(Cmd::DeclareFn(Function::CharToInt(Var::Temp)));
-        for c in iter::once('\n').chain(' '..='~') {
-            self.cmds.push(Cmd::StartCond(Expr::IsEqualLiteral(
-                Var::Temp,
-                Literal::Char(c),
-            )));
-            self.cmds
-                .push(Cmd::Return(Expr::Literal(Literal::Int(c as u8))));
-            self.cmds.push(Cmd::EndIf);
+}
+
+/// The pointer offsets and factors of the multiply loop (such as
+/// `[->++>+<<]`) at the start of `instrs`, with the number of instructions
+/// it spans. A multiply loop subtracts 1 from its cell per iteration, leaves
+/// the pointer where it started and only adds to other cells, by factors
+/// that fit in a `Literal`.
+fn multiply_loop(instrs: &[BfInstruction]) -> Option<(Vec<(i64, i64)>, usize)> {
+    if instrs.first() != Some(&BfInstruction::LoopStart) {
+        return None;
+    }
+    let mut offset = 0i64;
+    let mut factors: Vec<(i64, i64)> = Vec::new();
+    for (i, instr) in instrs.iter().enumerate().skip(1) {
+        let amount = match instr {
+            BfInstruction::IncPointer => {
+                offset += 1;
+                continue;
+            }
+            BfInstruction::DecPointer => {
+                offset -= 1;
+                continue;
+            }
+            BfInstruction::IncData => 1,
+            BfInstruction::DecData => -1,
+            BfInstruction::AddData(n) => *n as i64,
+            BfInstruction::LoopEnd => {
+                let control = factors.iter().position(|&(o, _)| o == 0)?;
+                if offset != 0 || factors.remove(control).1 != -1 {
+                    return None;
+                }
+                factors.retain(|&(_, k)| k != 0);
+                let fits = factors
+                    .iter()
+                    .all(|&(_, k)| (i8::MIN as i64..=u8::MAX as i64).contains(&k));
+                return (!factors.is_empty() && fits).then_some((factors, i + 1));
+            }
+            _ => return None,
+        };
+        match factors.iter_mut().find(|(o, _)| *o == offset) {
+            Some((_, k)) => *k += amount,
+            None => factors.push((offset, amount)),
         }
-        self.cmds.push(Cmd::Return(Expr::Literal(Literal::Int(0))));
     }
-    fn define_int_to_char(&mut self) {
-        self.cmds
-            .push(Cmd::DeclareFn(Function::IntToChar(Var::Temp)));
-        for i in iter::once(b'\n').chain(b' '..=b'~') {
-            self.cmds.push(Cmd::StartCond(Expr::IsEqualLiteral(
-                Var::Temp,
-                Literal::Int(i),
-            )));
-            self.cmds
-                .push(Cmd::Return(Expr::Literal(Literal::Char(i as char))));
-            self.cmds.push(Cmd::EndIf);
+    None
+}
+
+/// The cells that innermost loops print without changing them, as offsets
+/// from the pointer at the `[`, keyed by the index of the `[`, and the
+/// indices of the `.` instructions that print them. Only loops that leave
+/// the pointer where they found it qualify, so that offsets within the body
+/// are known, and only `.` whose value `known` does not give. Loops for
+/// which `skip` is true are left out.
+fn invariant_output_cells(
+    instrs: &[BfInstruction],
+    known: &[Option<i64>],
+    skip: impl Fn(usize) -> bool,
+) -> (HashMap<usize, i64>, HashSet<usize>) {
+    let balanced = opt::balanced_loops(instrs);
+    let mut cells = HashMap::new();
+    let mut outputs = HashSet::new();
+    for start in (0..instrs.len()).filter(|&i| balanced[i] && !skip(i)) {
+        let Some(len) = instrs[start + 1..]
+            .iter()
+            .position(|instr| matches!(instr, BfInstruction::LoopStart | BfInstruction::LoopEnd))
+        else {
+            continue;
+        };
+        let end = start + 1 + len;
+        if instrs[end] != BfInstruction::LoopEnd {
+            continue;
         }
-        self.cmds
-            .push(Cmd::Return(Expr::Literal(Literal::Char('$'))));
+        let positions = opt::pointer_positions(&instrs[start..=end], 0);
+        let offset = |i: usize| positions[i - start];
+        let written: HashSet<_> = (start + 1..end)
+            .filter(|&i| {
+                matches!(
+                    instrs[i],
+                    BfInstruction::IncData
+                        | BfInstruction::DecData
+                        | BfInstruction::AddData(_)
+                        | BfInstruction::ClearData
+                        | BfInstruction::Input
+                )
+            })
+            .map(offset)
+            .collect();
+        let printed = |i: usize| instrs[i] == BfInstruction::Output && known[i].is_none();
+        let Some(cell) = (start + 1..end)
+            .filter(|&i| printed(i))
+            .map(offset)
+            .find(|cell| !written.contains(cell))
+        else {
+            continue;
+        };
+        cells.insert(
+            start,
+            cell.expect("offsets within a balanced loop are known"),
+        );
+        outputs.extend((start + 1..end).filter(|&i| printed(i) && offset(i) == cell));
     }
-    fn declare_chorus(&mut self) {
-        self.cmds.push(Cmd::DeclareChorus);
+    (cells, outputs)
+}
+
+/// The loops `CompilerOptions::outline_loops` compiles into verses, as the
+/// range of instructions of each occurrence and the number of its verse.
+/// Verses are numbered from 1 in the order they are first used. Loops
+/// inside an extracted loop are compiled into its verse as usual.
+fn outlined_loops(instrs: &[BfInstruction]) -> Vec<(Range<usize>, usize)> {
+    // the maps here are only used for lookups and counts; the result is
+    // built by walking `instrs`, so their iteration order never reaches the
+    // output
+    // loops that could be extracted, by start
+    let mut candidates = HashMap::new();
+    // the start and pointer offset of each open loop, and whether it and
+    // every loop in it leave the pointer where they started
+    let mut open: Vec<(usize, i64, bool)> = Vec::new();
+    let mut offset = 0i64;
+    for (i, instr) in instrs.iter().enumerate() {
+        match instr {
+            BfInstruction::IncPointer => offset += 1,
+            BfInstruction::DecPointer => offset -= 1,
+            BfInstruction::Input | BfInstruction::AssertData(_) => {
+                for (_, _, extractable) in &mut open {
+                    *extractable = false;
+                }
+            }
+            BfInstruction::LoopStart => open.push((i, offset, true)),
+            BfInstruction::LoopEnd => {
+                let Some((start, start_offset, extractable)) = open.pop() else {
+                    continue;
+                };
+                let balanced = offset == start_offset;
+                if let Some(outer) = open.last_mut() {
+                    outer.2 &= balanced;
+                }
+                if extractable && balanced && i + 1 - start >= MIN_OUTLINED_LOOP_LEN {
+                    candidates.insert(start, start..i + 1);
+                }
+            }
+            _ => {}
+        }
     }
-    fn init_vars(&mut self) {
-        self.cmds.push(Cmd::DeclareVar(Var::Zero));
-        self.cmds.push(Cmd::DeclareVar(Var::Tape));
-        self.cmds.push(Cmd::DeclareVar(Var::Temp));
-        self.cmds.push(Cmd::DeclareVar(Var::Buffer));
-        self.cmds.push(Cmd::DeclareVar(Var::Pointer));
-        self.cmds
-            .push(Cmd::Assign(Var::Zero, Expr::Literal(Literal::Int(0))));
-        self.cmds
-            .push(Cmd::Assign(Var::Tape, Expr::Literal(Literal::EmptyArray)));
-        self.cmds.push(Cmd::Call(
-            Function::ArrayPush(Var::Tape, Var::Zero, Var::Zero),
-            Var::Tape,
-        ));
-        self.cmds
-            .push(Cmd::Assign(Var::Temp, Expr::Literal(Literal::Int(0))));
-        self.cmds
-            .push(Cmd::Assign(Var::Buffer, Expr::Literal(Literal::EmptyArray)));
-        self.cmds
-            .push(Cmd::Assign(Var::Pointer, Expr::Literal(Literal::Int(0))));
+    let mut repeated: HashMap<&[BfInstruction], usize> = HashMap::new();
+    for range in candidates.values() {
+        *repeated.entry(&instrs[range.clone()]).or_default() += 1;
     }
-    fn inc_pointer(&mut self) {
-        self.cmds
-            .push(Cmd::Assign(Var::Pointer, Expr::Inc(Var::Pointer)));
-        self.cmds
-            .push(Cmd::Call(Function::ArrayLength(Var::Tape), Var::Temp));
-        self.cmds
-            .push(Cmd::StartCond(Expr::IsEqualVar(Var::Pointer, Var::Temp)));
-        self.cmds.push(Cmd::Call(
-            Function::ArrayPush(Var::Tape, Var::Temp, Var::Zero),
-            Var::Tape,
-        ));
-        self.cmds.push(Cmd::EndIf);
+    loop {
+        // the outermost candidates that occur several times
+        let mut used = Vec::new();
+        let mut i = 0;
+        while i < instrs.len() {
+            match candidates.get(&i) {
+                Some(range) if repeated.get(&instrs[range.clone()]) > Some(&1) => {
+                    used.push(range.clone());
+                    i = range.end;
+                }
+                _ => i += 1,
+            }
+        }
+        let mut uses: HashMap<&[BfInstruction], usize> = HashMap::new();
+        for range in &used {
+            *uses.entry(&instrs[range.clone()]).or_default() += 1;
+        }
+        if uses.values().all(|&n| n > 1) {
+            let mut numbers: HashMap<&[BfInstruction], usize> = HashMap::new();
+            return used
+                .into_iter()
+                .map(|range| {
+                    let next = numbers.len() + 1;
+                    let n = *numbers.entry(&instrs[range.clone()]).or_insert(next);
+                    (range, n)
+                })
+                .collect();
+        }
+        // loops whose other occurrences are all inside extracted loops
+        repeated.retain(|body, _| uses.get(body).is_none_or(|&n| n > 1));
     }
-    fn dec_pointer(&mut self) {
-        self.cmds
-            .push(Cmd::Assign(Var::Pointer, Expr::Dec(Var::Pointer)));
+}
+
+/// Compile-time equivalent of the `IntToChar` verse
+fn int_to_char(value: i64) -> char {
+    match value {
+        10 | 32..=126 => value as u8 as char,
+        _ => '$',
     }
-    fn inc_data(&mut self) {
-        self.cmds.push(Cmd::Assign(
-            Var::Temp,
-            Expr::ArrayAccess(Var::Tape, Var::Pointer),
-        ));
-        self.cmds.push(Cmd::Assign(Var::Temp, Expr::Inc(Var::Temp)));
-        self.cmds.push(Cmd::Call(
-            Function::ArrayReplace(Var::Tape, Var::Pointer, Var::Temp),
-            Var::Tape,
-        ));
+}
+
+/// The explanation of `+`, `-` or a run of them that adds `amount` in all,
+/// see `CompilerOptions::explain`
+fn explain_addition(amount: i32) -> String {
+    let (verb, preposition) = if amount < 0 {
+        ("subtracts", "from")
+    } else {
+        ("adds", "to")
+    };
+    format!(
+        "{} {} {} {} the current cell: Rickroll cannot change an array element in \
+         place, so the cell is copied into Temp, changed and written back with \
+         ArrayReplace",
+        BfInstruction::AddData(amount),
+        verb,
+        amount.unsigned_abs(),
+        preposition
+    )
+}
+
+/// What a `Warning` is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// The program has no `.` command, so it never prints anything
+    NoOutput,
+    /// A `[]` loop, which never ends once it is entered
+    EmptyLoop,
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoOutput => write!(f, "the program never produces output"),
+            Self::EmptyLoop => write!(f, "the loop `[]` never ends once it is entered"),
+        }
     }
-    fn dec_data(&mut self) {
-        self.cmds.push(Cmd::Assign(
-            Var::Temp,
-            Expr::ArrayAccess(Var::Tape, Var::Pointer),
-        ));
-        self.cmds.push(Cmd::Assign(Var::Temp, Expr::Dec(Var::Temp)));
-        self.cmds.push(Cmd::Call(
-            Function::ArrayReplace(Var::Tape, Var::Pointer, Var::Temp),
-            Var::Tape,
-        ));
+}
+
+/// A likely mistake in a Brainfuck program, see `Compiler::warnings`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    /// Position in the source program of the instruction the warning is
+    /// about, or `None` if it is about the whole program
+    pub position: Option<usize>,
+}
+
+/// How the running time of a program grows with the values of the cells
+/// that control its loops, see `Compiler::estimate_runtime_complexity`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BigO {
+    /// The program has no loops, or every loop runs a known number of times
+    Constant,
+    Linear,
+    Quadratic,
+    /// Counting loops nested the given number of levels deep, at least 3
+    Polynomial(u32),
+    /// A loop multiplies the cell of one of its inner loops by 2 or more
+    /// every iteration
+    Exponential,
+    /// The loops follow no pattern the estimator understands, such as `[>]`
+    #[default]
+    Unknown,
+}
+
+impl fmt::Display for BigO {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Constant => write!(f, "O(1)"),
+            Self::Linear => write!(f, "O(n)"),
+            Self::Quadratic => write!(f, "O(n^2)"),
+            Self::Polynomial(degree) => write!(f, "O(n^{})", degree),
+            Self::Exponential => write!(f, "O(2^n)"),
+            Self::Unknown => write!(f, "unknown"),
+        }
     }
-    fn output_byte(&mut self) {
-        self.cmds.push(Cmd::Assign(
-            Var::Temp,
-            Expr::ArrayAccess(Var::Tape, Var::Pointer),
-        ));
+}
+
+/// Static measurements of the generated program
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompilerStats {
+    /// Number of places the tape is read
+    pub tape_reads: usize,
+    /// Number of places the tape is written
+    pub tape_writes: usize,
+    /// See `Compiler::count_loop_iterations_bound`
+    pub loop_iterations_bound: Option<u64>,
+    /// See `Compiler::number_of_unique_functions`
+    pub unique_functions: usize,
+    /// See `Compiler::estimate_runtime_complexity`
+    pub complexity: BigO,
+}
+
+/// How much `Compiler::from_optimized` shrinks a program, see
+/// `Compiler::output_diff_from_unoptimized`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OptimizationReport {
+    pub unoptimized_lines: usize,
+    pub optimized_lines: usize,
+    /// Share of the unoptimized lines that optimization removed
+    pub reduction_percent: f64,
+    /// What each optimization did, in the order they ran
+    pub optimization_notes: Vec<String>,
+}
+
+/// Decodes a base64-encoded Brainfuck program, ignoring whitespace such as
+/// line breaks
+pub fn decode_base64(encoded: &str) -> Result<String, CompilerError> {
+    let encoded: Vec<u8> = encoded
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    let bytes = BASE64
+        .decode(encoded)
+        .map_err(|_| CompilerError::InvalidBase64)?;
+    String::from_utf8(bytes).map_err(|_| CompilerError::InvalidBase64)
+}
+
+/// SHA-256 hash of the Brainfuck commands in a parsed program, written as
+/// standard Brainfuck without comments, assertions or extensions
+#[cfg(feature = "hash")]
+fn source_hash(instrs: &[(usize, BfInstruction)]) -> [u8; 32] {
+    let commands: String = instrs
+        .iter()
+        .filter(|(_, instr)| !matches!(instr, BfInstruction::AssertData(_) | BfInstruction::Sleep))
+        .map(|(_, instr)| instr.to_string())
+        .collect();
+    Sha256::digest(commands).into()
+}
+
+/// Splits a source into the program and, with `split_on_bang`, the input
+/// following the first `!`. Only standard Brainfuck sources are split, as
+/// `!` is part of other dialects.
+fn split_input<'a>(source: &'a str, opts: &CompilerOptions) -> (&'a str, Option<&'a str>) {
+    match source.split_once('!') {
+        Some((program, input)) if opts.split_on_bang && opts.dialect == BfDialect::Standard => {
+            (program, Some(input))
+        }
+        _ => (source, None),
+    }
+}
+
+/// The instructions in `range`, together with the position of the first one
+fn select_range(
+    instrs: &[BfInstruction],
+    range: Option<(usize, usize)>,
+) -> (&[BfInstruction], usize) {
+    match range {
+        Some((start, end)) => {
+            let end = end.min(instrs.len());
+            let start = start.min(end);
+            (&instrs[start..end], start)
+        }
+        None => (instrs, 0),
+    }
+}
+
+pub struct Compiler {
+    cmds: Vec<Cmd>,
+    /// The Brainfuck instructions the commands were generated from
+    instrs: Vec<BfInstruction>,
+    /// The position of each of `instrs` in the source program
+    origins: Vec<usize>,
+    /// The index in `cmds` of the first command generated for each of
+    /// `instrs`. Commands must only be removed through `splice_cmds` and
+    /// `retain_cmds`, which keep it up to date.
+    instr_starts: Vec<usize>,
+    /// The byte offset in the source of each instruction in the source
+    /// program, indexed by position as in `origins`
+    source_offsets: Vec<usize>,
+    check_bounds: bool,
+    /// Whether changes of a cell are checked for wrapping around, see
+    /// `CompilerOptions::wrapping`
+    wrap_cells: bool,
+    debug_print_on_loop: bool,
+    seed_input: Option<String>,
+    input_from_arg: bool,
+    pointer_start: usize,
+    /// See `Compiler::set_tape_initial_values`
+    tape_init: Vec<u8>,
+    pointer_type: PointerType,
+    /// See `Compiler::input_hash`
+    #[cfg(feature = "hash")]
+    input_hash: [u8; 32],
+    warnings: Vec<Warning>,
+}
+
+impl Compiler {
+    /// Compiles a program without optimizing it, failing like
+    /// `from_optimized` if it is empty (unless `CompilerOptions::allow_empty`
+    /// is set) or its brackets are unbalanced
+    pub fn read(program: &str, opts: &CompilerOptions) -> Result<Compiler, CompilerError> {
+        let (instrs, origins) = Self::checked_instructions_with_origins(program, opts)?;
+        let mut compiler = Self::from_instructions(program, &instrs, &origins, opts);
+        if let Some(max_lines) = opts.max_chorus_lines {
+            compiler.split_chorus(max_lines);
+        }
+        compiler.verify_bracket_balance()?;
+        Ok(compiler)
+    }
+    pub fn from_optimized(
+        program: &str,
+        opts: &CompilerOptions,
+    ) -> Result<Compiler, CompilerError> {
+        let (instrs, origins) = Self::optimized_instructions_with_origins(program, opts)?;
+        let mut compiler = Self::from_instructions(program, &instrs, &origins, opts);
+        compiler.optimize_cmds(opts);
+        if let Some(max_lines) = opts.max_chorus_lines {
+            compiler.split_chorus(max_lines);
+        }
+        compiler.verify_bracket_balance()?;
+        Ok(compiler)
+    }
+    /// Checks that every line of Rickroll code (such as the result of
+    /// `output`) has the form of one of the statements the compiler
+    /// generates, ignoring indentation, and reports the lines that do not
+    #[cfg(feature = "validate")]
+    pub fn validate_rickroll_syntax(output: &str) -> Result<(), Vec<String>> {
+        static LINES: OnceLock<RegexSet> = OnceLock::new();
+        let lines = LINES.get_or_init(|| {
+            RegexSet::new([
+                r"^$",
+                r"^#.*$",
+                r"^\[Verse \w+\]$",
+                r"^\[Chorus( .+)?\]$",
+                r"^\(Ooh give you \w+(, \w+)*\)$",
+                r"^Never gonna let \w+ down$",
+                r"^Never gonna give \w+ .+$",
+                r"^\(Ooh\) Never gonna give, never gonna give \(give you .+\)$",
+                r"^(\(Ooh give you \w+\) )?Never gonna run \w+ and desert .+$",
+                r"^Inside we both know .+$",
+                r"^Your heart's been aching but you're too shy to say it$",
+                r"^We know the game and we're gonna play it$",
+                r"^Never gonna say \d+$",
+            ])
+            .expect("line patterns are valid")
+        });
+        let errors: Vec<String> = output
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !lines.is_match(line.trim()))
+            .map(|(i, line)| format!("line {}: unrecognized statement \"{}\"", i + 1, line))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    /// Compiles a program both without optimization and with `opts`, and
+    /// compares the number of lines in the outputs
+    pub fn output_diff_from_unoptimized(
+        program: &str,
+        opts: &CompilerOptions,
+    ) -> Result<OptimizationReport, CompilerError> {
+        let unoptimized_opts = CompilerOptions {
+            opt_level: 0,
+            passes: None,
+            ..opts.clone()
+        };
+        let unoptimized = Self::from_optimized(program, &unoptimized_opts)?;
+        let unoptimized_lines = unoptimized.lines(&unoptimized_opts).count();
+
+        let (mut instrs, mut origins) =
+            Self::optimized_instructions_with_origins(program, &unoptimized_opts)?;
+        let mut notes = Vec::new();
+        for pass in Self::pipeline(opts).passes() {
+            let before = instrs.len();
+            (instrs, origins) = pass.run_with_origins(&instrs, &origins);
+            notes.push(format!(
+                "{}: {} -> {} instructions",
+                pass.name(),
+                before,
+                instrs.len()
+            ));
+        }
+        let mut compiler = Self::from_instructions(program, &instrs, &origins, opts);
+        notes.extend(compiler.optimize_cmds(opts));
+        compiler.verify_bracket_balance()?;
+        let optimized_lines = compiler.lines(opts).count();
+
+        let reduction_percent = if unoptimized_lines == 0 {
+            0.0
+        } else {
+            100.0 * (1.0 - optimized_lines as f64 / unoptimized_lines as f64)
+        };
+        Ok(OptimizationReport {
+            unoptimized_lines,
+            optimized_lines,
+            reduction_percent,
+            optimization_notes: notes,
+        })
+    }
+    /// Runs the optimizations on generated commands that `opts` enables and
+    /// describes the ones that changed anything
+    fn optimize_cmds(&mut self, opts: &CompilerOptions) -> Vec<String> {
+        let mut notes = Vec::new();
+        if opts.opt_level == 0 {
+            return notes;
+        }
+        if opts.opt_level >= 2 {
+            let baked = self.bake_constants();
+            if baked > 0 {
+                notes.push(format!(
+                    "replaced {} reads of constant cells with their value",
+                    baked
+                ));
+            }
+        }
+        if opts.opt_level >= 2 {
+            let unrolled = self.unroll_small_loops(MAX_UNROLLED_ITERATIONS);
+            if unrolled > 0 {
+                notes.push(format!("unrolled {} loops that run a few times", unrolled));
+            }
+        }
+        if opts.opt_level >= 2 {
+            let fused = self.fuse_adjacent_putchar_calls();
+            if fused > 0 {
+                notes.push(format!(
+                    "printed {} groups of known characters with a single PutStr",
+                    fused
+                ));
+            }
+        }
+        let reloads = self.forward_tape_stores();
+        if reloads > 0 {
+            notes.push(format!(
+                "removed {} reloads of a cell that was just stored",
+                reloads
+            ));
+        }
+        let inlined = self.optimize_tail_calls();
+        if inlined > 0 {
+            notes.push(format!(
+                "inlined {} verses called at the end of a verse",
+                inlined
+            ));
+        }
+        let before = self.cmds.len();
+        self.eliminate_dead_tape_writes();
+        if self.cmds.len() < before {
+            notes.push(format!(
+                "removed {} overwritten tape writes",
+                before - self.cmds.len()
+            ));
+        }
+        let before = self.cmds.len();
+        self.eliminate_unused_vars();
+        if self.cmds.len() < before {
+            notes.push(format!(
+                "removed {} declarations and initializations of unused variables",
+                before - self.cmds.len()
+            ));
+        }
+        if opts.opt_level >= 2 {
+            let renamed = self.reuse_scratch_vars();
+            if renamed > 0 {
+                notes.push(format!(
+                    "merged {} scratch variables into others that were free",
+                    renamed
+                ));
+            }
+        }
+        for note in &notes {
+            log::debug!("{}", note);
+        }
+        notes
+    }
+    /// Reads a Brainfuck file and compiles it with `from_optimized`
+    pub fn from_path(
+        path: impl AsRef<Path>,
+        opts: &CompilerOptions,
+    ) -> Result<Compiler, CompilerError> {
+        let program = fs::read_to_string(path)?;
+        Self::from_optimized(&program, opts)
+    }
+    /// Decodes a base64-encoded program, which may be split across lines,
+    /// and compiles it with `from_optimized`
+    pub fn from_base64_encoded_bf(
+        encoded: &str,
+        opts: &CompilerOptions,
+    ) -> Result<Compiler, CompilerError> {
+        let program = decode_base64(encoded)?;
+        Self::from_optimized(&program, opts)
+    }
+    /// Compiles a program with `from_optimized` and returns its output
+    pub fn compile_str(program: &str, opts: &CompilerOptions) -> Result<String, CompilerError> {
+        Self::from_optimized(program, opts)?.output(opts)
+    }
+    /// The instructions `from_optimized` generates code for
+    pub fn optimized_instructions(
+        program: &str,
+        opts: &CompilerOptions,
+    ) -> Result<Vec<BfInstruction>, CompilerError> {
+        Ok(Self::optimized_instructions_with_origins(program, opts)?.0)
+    }
+    /// `optimized_instructions`, together with the position in the source
+    /// program of each of them
+    fn optimized_instructions_with_origins(
+        program: &str,
+        opts: &CompilerOptions,
+    ) -> Result<(Vec<BfInstruction>, Vec<usize>), CompilerError> {
+        let (mut instrs, mut origins) = Self::checked_instructions_with_origins(program, opts)?;
+        Self::pipeline(opts).run_with_origins(&mut instrs, &mut origins);
+        Ok((instrs, origins))
+    }
+    /// The instructions in `CompilerOptions::range` and their positions,
+    /// rejecting empty programs and unbalanced brackets
+    fn checked_instructions_with_origins(
+        program: &str,
+        opts: &CompilerOptions,
+    ) -> Result<(Vec<BfInstruction>, Vec<usize>), CompilerError> {
+        let instrs = Self::source_instructions(program, opts);
+        if instrs.is_empty() && !opts.allow_empty {
+            return Err(CompilerError::EmptyProgram);
+        }
+        let (instrs, start) = select_range(&instrs, opts.range);
+        BfParser::check_balance(instrs)?;
+        Ok((instrs.to_vec(), (start..start + instrs.len()).collect()))
+    }
+    fn pipeline(opts: &CompilerOptions) -> PassPipeline {
+        let pipeline = match &opts.passes {
+            Some(passes) => PassPipeline::new(passes.clone()),
+            None => PassPipeline::for_level(opts.opt_level),
+        };
+        if opts.tape_init.is_empty() {
+            return pipeline;
+        }
+        let passes = pipeline.passes().iter().copied();
+        PassPipeline::new(passes.filter(|pass| !pass.assumes_zero_tape()).collect())
+    }
+    /// The parsed program, without assertions unless they are enabled
+    fn source_instructions(program: &str, opts: &CompilerOptions) -> Vec<BfInstruction> {
+        let instrs: Vec<BfInstruction> = Self::parse_source(program, opts)
+            .into_iter()
+            .map(|(_, instr)| instr)
+            .collect();
+        log::debug!("parsed {} instructions", instrs.len());
+        instrs
+    }
+    /// `source_instructions` with the byte offset of each instruction in
+    /// the source
+    fn parse_source(program: &str, opts: &CompilerOptions) -> Vec<(usize, BfInstruction)> {
+        let (program, _) = split_input(program, opts);
+        let mut instrs = BfParser::dialect_instructions_with_offsets(
+            program,
+            opts.dialect,
+            opts.sleep_char,
+            opts.comment_char,
+        );
+        if !opts.assertions {
+            instrs.retain(|(_, instr)| !matches!(instr, BfInstruction::AssertData(_)));
+        }
+        instrs
+    }
+    fn from_instructions(
+        program: &str,
+        instrs: &[BfInstruction],
+        origins: &[usize],
+        opts: &CompilerOptions,
+    ) -> Compiler {
+        let source = Self::parse_source(program, opts);
+        let mut compiler = Self {
+            cmds: Vec::new(),
+            instrs: instrs.to_vec(),
+            origins: origins.to_vec(),
+            instr_starts: Vec::with_capacity(instrs.len()),
+            source_offsets: source.iter().map(|(offset, _)| *offset).collect(),
+            check_bounds: opts.check_bounds,
+            wrap_cells: opts.wrapping && !opts.no_wrap_check,
+            debug_print_on_loop: opts.debug_print_on_loop,
+            seed_input: split_input(program, opts)
+                .1
+                .map(str::to_owned)
+                .or_else(|| opts.seed_input.clone()),
+            input_from_arg: opts.input_from_arg,
+            pointer_start: opts.pointer_start,
+            tape_init: Vec::new(),
+            pointer_type: opts.pointer_type,
+            #[cfg(feature = "hash")]
+            input_hash: source_hash(&source),
+            warnings: find_warnings(instrs, origins),
+        };
+        let put_int = instrs
+            .iter()
+            .any(|instr| matches!(instr, BfInstruction::AssertData(_)));
+        let outlined = if opts.outline_loops
+            && opts.preamble
+            && !opts.trace
+            && !opts.check_bounds
+            && opts.pointer_type == PointerType::Index
+        {
+            outlined_loops(instrs)
+        } else {
+            Vec::new()
+        };
+        let array_search = opts.array_search
+            && !opts.trace
+            && !opts.debug_print_on_loop
+            && opts.pointer_type == PointerType::Index;
+        // folding multiply loops and hoisting reads out of loops
+        let rewrite_loops = opts.opt_level >= 2
+            && opts.preamble
+            && !opts.trace
+            && !opts.debug_print_on_loop
+            && !opts.check_bounds
+            && opts.pointer_type == PointerType::Index;
+        let fold_multiply_loops = rewrite_loops && !compiler.wrap_cells;
+        // loops that `unroll_small_loops` unrolls are left to it, as their
+        // cells end up known
+        let iterations = if rewrite_loops && opts.tape_init.is_empty() {
+            opt::loop_iterations(instrs)
+        } else {
+            vec![None; instrs.len()]
+        };
+        let unrolled = |i: usize| {
+            iterations[i].is_some_and(|k| (1..=MAX_UNROLLED_ITERATIONS as u64).contains(&k))
+        };
+        let folded =
+            |i: usize| multiply_loop(&instrs[i..]).filter(|_| fold_multiply_loops && !unrolled(i));
+        let multiply_loops = (0..instrs.len()).any(|i| folded(i).is_some());
+        let concat_input = opts.input_from_arg && compiler.seed_input.is_some();
+        let known = if opts.opt_level > 0 && opts.tape_init.is_empty() {
+            let values = opt::current_cell_values(instrs);
+            if opts.wrapping {
+                let wrap = |value: Option<i64>| value.map(|v| v.rem_euclid(256));
+                values.into_iter().map(wrap).collect()
+            } else {
+                values
+            }
+        } else {
+            vec![None; instrs.len()]
+        };
+        let (cached_cells, cached_outputs) = if rewrite_loops {
+            let outlined = |i: usize| outlined.iter().any(|(range, _)| range.contains(&i));
+            invariant_output_cells(instrs, &known, |i| unrolled(i) || outlined(i))
+        } else {
+            (HashMap::new(), HashSet::new())
+        };
+        if opts.preamble && opts.layout == Layout::VersesFirst {
+            compiler.define_char_to_int();
+            compiler.define_int_to_char();
+            if put_int {
+                compiler.define_put_int();
+            }
+            if concat_input {
+                compiler.define_array_concat();
+            }
+            compiler.define_loops(instrs, &outlined);
+        }
+        if opts.preamble {
+            compiler.declare_chorus(opts.chorus_label.clone());
+            let mut extra = Vec::new();
+            if multiply_loops {
+                extra.push(Var::Count);
+            }
+            if !cached_cells.is_empty() {
+                extra.push(Var::Cached);
+            }
+            compiler.init_vars(&extra);
+        }
+        // the end of the outlined loop being skipped
+        let mut skip_until = 0;
+        for (i, ((instr, known), origin)) in instrs.iter().zip(known).zip(origins).enumerate() {
+            compiler.instr_starts.push(compiler.cmds.len());
+            if i < skip_until {
+                continue;
+            }
+            if let Some((range, n)) = outlined.iter().find(|(range, _)| range.start == i) {
+                compiler.cmds.push(Cmd::Call(Function::Loop(*n), Var::Tape));
+                skip_until = range.end;
+                continue;
+            }
+            if let Some(step) = scan_loop_step(&instrs[i..]).filter(|_| array_search) {
+                compiler.scan_for_zero(step);
+                skip_until = i + 3;
+                continue;
+            }
+            if let Some((factors, len)) = folded(i) {
+                compiler.fold_multiply_loop(&factors);
+                skip_until = i + len;
+                continue;
+            }
+            if let Some(&cell) = cached_cells.get(&i) {
+                compiler.cache_output_char(cell);
+            }
+            if opts.explain {
+                let explanation = compiler.explain_instruction(instr);
+                compiler.cmds.push(Cmd::Comment(explanation));
+            }
+            if opts.trace {
+                compiler.cmds.push(Cmd::Trace(*origin));
+            }
+            if cached_outputs.contains(&i) {
+                compiler
+                    .cmds
+                    .push(Cmd::CallNoReturn(Function::PutChar(Var::Cached)));
+                continue;
+            }
+            compiler.emit_instruction(instr, known);
+        }
+        if opts.preamble && opts.layout == Layout::ChorusFirst {
+            compiler.define_char_to_int();
+            compiler.define_int_to_char();
+            if put_int {
+                compiler.define_put_int();
+            }
+            if concat_input {
+                compiler.define_array_concat();
+            }
+            compiler.define_loops(instrs, &outlined);
+        }
+        let cells = match opts.tape_preallocate {
+            Some(TapePreallocate::Cells(cells)) => cells,
+            Some(TapePreallocate::Auto) => match compiler.max_pointer_value() {
+                Some(max) => max + 1,
+                None => compiler.tape_cell_count_lower_bound(),
+            },
+            None => 1,
+        };
+        compiler.preallocate_tape(cells.max(opts.pointer_start.saturating_add(1)));
+        if !opts.tape_init.is_empty() {
+            compiler.set_tape_initial_values(&opts.tape_init);
+        }
+        if opts.embed_source {
+            compiler.embed_source(program);
+        }
+        log::debug!(
+            "generated {} commands from {} instructions",
+            compiler.cmds.len(),
+            instrs.len()
+        );
+        compiler
+    }
+    pub fn output(&self, opts: &CompilerOptions) -> Result<String, CompilerError> {
+        let mut res = String::new();
+        self.output_into(&mut res, opts)?;
+        Ok(res)
+    }
+    /// `output`, appended to `buf` so that its allocation can be reused when
+    /// compiling many programs. On error, `buf` may hold part of the output.
+    pub fn output_into(
+        &self,
+        buf: &mut String,
+        opts: &CompilerOptions,
+    ) -> Result<(), CompilerError> {
+        let mut lines = 0;
+        for line in self.lines(opts) {
+            buf.push_str(&line?);
+            buf.push('\n');
+            lines += 1;
+        }
+        log::debug!("emitted {} lines", lines);
+        Ok(())
+    }
+    /// `output` with each line prefixed by its right-aligned 1-based number,
+    /// as in `  1: [Verse CharToInt]`
+    pub fn output_with_line_numbers(
+        &self,
+        opts: &CompilerOptions,
+    ) -> Result<String, CompilerError> {
+        let output = self.output(opts)?;
+        let width = output.lines().count().max(1).ilog10() as usize + 1;
+        let mut res = String::with_capacity(output.len());
+        for (i, line) in output.lines().enumerate() {
+            writeln!(res, "{:>width$}: {}", i + 1, line, width = width)?;
+        }
+        Ok(res)
+    }
+    /// `output` preceded by comments describing the build, one `# key: value`
+    /// line each after a `# brickroll-stats 1` line, for tools that keep
+    /// track of generated files. The keys are, in order: `version`,
+    /// `timestamp` (Unix seconds, taken from `SOURCE_DATE_EPOCH` if it is set
+    /// for reproducible builds), `source-sha256` (`none` without the `hash`
+    /// feature), `bf-instructions`, `rickroll-lines` (not counting the
+    /// header), `opt-level` and `max-loop-depth`. Keys are only ever added
+    /// at the end, so that readers of older headers keep working.
+    pub fn output_with_statistics_header(
+        &self,
+        opts: &CompilerOptions,
+    ) -> Result<String, CompilerError> {
+        let output = self.output(opts)?;
+        let timestamp = env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|epoch| epoch.parse::<u64>().ok())
+            .unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs())
+            });
+        #[cfg(feature = "hash")]
+        let hash: String = self
+            .input_hash
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        #[cfg(not(feature = "hash"))]
+        let hash = "none";
+        let mut depth = 0usize;
+        let mut max_depth = 0;
+        for instr in &self.instrs {
+            match instr {
+                BfInstruction::LoopStart => {
+                    depth += 1;
+                    max_depth = max_depth.max(depth);
+                }
+                BfInstruction::LoopEnd => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        let mut res = String::with_capacity(output.len() + 256);
+        writeln!(res, "# brickroll-stats 1")?;
+        writeln!(res, "# version: {}", env!("CARGO_PKG_VERSION"))?;
+        writeln!(res, "# timestamp: {}", timestamp)?;
+        writeln!(res, "# source-sha256: {}", hash)?;
+        writeln!(res, "# bf-instructions: {}", self.source_offsets.len())?;
+        writeln!(res, "# rickroll-lines: {}", output.lines().count())?;
+        writeln!(res, "# opt-level: {}", opts.opt_level)?;
+        writeln!(res, "# max-loop-depth: {}", max_depth)?;
+        res.push_str(&output);
+        Ok(res)
+    }
+    /// Runs the generated program without input and writes what it prints,
+    /// including the positions printed by trace statements, to `writer`.
+    /// A program that reads input is run until its first read, after which
+    /// a `# waiting for input at instruction N` line is written. Fails with
+    /// `CompilerError::ExecutionError` if the program does something with
+    /// no defined meaning, or does not finish within 10 million commands.
+    pub fn trace_execution<W: Write>(&self, writer: &mut W) -> Result<(), CompilerError> {
+        match emulate::run(&self.cmds, writer) {
+            Ok(Outcome::Finished) => Ok(()),
+            Ok(Outcome::WaitingForInput(cmd)) => {
+                match self.position_of_cmd(cmd) {
+                    Some(position) => {
+                        writeln!(writer, "# waiting for input at instruction {}", position)?
+                    }
+                    None => writeln!(writer, "# waiting for input")?,
+                }
+                Ok(())
+            }
+            Err(fault) => Err(CompilerError::ExecutionError {
+                position: fault.cmd.and_then(|cmd| self.position_of_cmd(cmd)),
+                message: fault.message,
+            }),
+        }
+    }
+    /// Position in the source program of the instruction that generated the
+    /// command at `index`, or `None` for commands of the preamble
+    fn position_of_cmd(&self, index: usize) -> Option<usize> {
+        let instr = self.instr_starts.partition_point(|&start| start <= index);
+        instr.checked_sub(1).map(|instr| self.origins[instr])
+    }
+    /// Only the `[Verse ...]` definitions of `output`, without the chorus,
+    /// for other Rickroll programs to include. This is the part of the
+    /// output that `CompilerOptions::preamble` leaves out, so it is empty
+    /// without the preamble.
+    pub fn as_library_shim(&self, opts: &CompilerOptions) -> Result<String, CompilerError> {
+        self.verify_bracket_balance()?;
+        let mut res = String::new();
+        for (decl, end) in self.verse_bodies() {
+            let mut level = 0i64;
+            for cmd in &self.cmds[decl..end] {
+                res.push_str(&Self::format_cmd(cmd, &mut level, opts)?);
+            }
+        }
+        Ok(res)
+    }
+    /// `output` split into chunks of at most `chunk_size` lines, for
+    /// runtimes that can only take a limited number of lines at a time.
+    /// Chunks only end between verses or between top-level commands of the
+    /// chorus, so a verse or top-level loop that is longer than `chunk_size`
+    /// gets a longer chunk of its own.
+    pub fn chunk_output(
+        &self,
+        chunk_size: usize,
+        opts: &CompilerOptions,
+    ) -> Result<Vec<String>, CompilerError> {
+        self.verify_bracket_balance()?;
+        // the runs of lines that must stay in the same chunk, with their
+        // lengths
+        let mut units: Vec<(String, usize)> = Vec::new();
+        let mut level = 0i64;
+        let mut in_verse = false;
+        for cmd in &self.cmds {
+            let starts_verse = matches!(cmd, Cmd::DeclareFn(_) | Cmd::DeclareChorus(..));
+            if units.is_empty() || (level == 0 && (!in_verse || starts_verse)) {
+                units.push((String::new(), 0));
+            }
+            let text = Self::format_cmd(cmd, &mut level, opts)?;
+            let unit = units.last_mut().expect("a unit was just pushed");
+            unit.0.push_str(&text);
+            unit.1 += text.lines().count();
+            match cmd {
+                Cmd::DeclareFn(_) => in_verse = true,
+                Cmd::DeclareChorus(..) => in_verse = false,
+                _ => {}
+            }
+        }
+        let mut chunks: Vec<(String, usize)> = Vec::new();
+        for (text, lines) in units {
+            match chunks.last_mut() {
+                Some(chunk) if chunk.1 + lines <= chunk_size => {
+                    chunk.0.push_str(&text);
+                    chunk.1 += lines;
+                }
+                _ => chunks.push((text, lines)),
+            }
+        }
+        Ok(chunks.into_iter().map(|(text, _)| text).collect())
+    }
+    /// Moves the end of the chorus into the verses `Section1`, `Section2`
+    /// and so on, which the chorus and then each section call in turn, so
+    /// that none of them is longer than `max_lines` lines. Like chunks of
+    /// `chunk_output`, sections only end between top-level commands, so a
+    /// top-level loop that does not fit gets a longer section of its own.
+    /// Each section takes the variables the chorus has declared before it.
+    /// Does nothing without a chorus header, and returns the number of
+    /// sections.
+    pub fn split_chorus(&mut self, max_lines: usize) -> usize {
+        let Some(chorus) = self
+            .cmds
+            .iter()
+            .position(|cmd| matches!(cmd, Cmd::DeclareChorus(..)))
+        else {
+            return 0;
+        };
+        let end = self.cmds[chorus + 1..]
+            .iter()
+            .position(|cmd| matches!(cmd, Cmd::DeclareFn(_)))
+            .map_or(self.cmds.len(), |i| chorus + 1 + i);
+        // every command takes one line, except for the headers; the chorus
+        // also needs a line for calling the first section, and a section
+        // two for its header, one for calling the next and one for returning
+        let param = match &self.cmds[chorus] {
+            Cmd::DeclareChorus(_, param) => *param,
+            _ => unreachable!("the chorus was found by its header"),
+        };
+        let mut used = if param.is_some() { 3 } else { 2 };
+        let mut starts = Vec::new();
+        let mut level = 0i64;
+        let mut unit_start = chorus + 1;
+        for i in chorus + 1..end {
+            match self.cmds[i] {
+                Cmd::StartCond(_) => level += 1,
+                Cmd::EndIf | Cmd::EndWhile => level -= 1,
+                _ => {}
+            }
+            if level > 0 {
+                continue;
+            }
+            let lines = i + 1 - unit_start;
+            let section_start = starts.last().map_or(chorus + 1, |&s| s);
+            if used + lines > max_lines && unit_start > section_start {
+                starts.push(unit_start);
+                used = 4;
+            }
+            used += lines;
+            unit_start = i + 1;
+        }
+        if starts.is_empty() {
+            return 0;
+        }
+        let done = Cmd::Return(Expr::Literal(Literal::Int(0)));
+        self.splice_cmds(end..end, vec![done.clone()]);
+        for (k, &start) in starts.iter().enumerate().rev() {
+            let vars = param
+                .iter()
+                .copied()
+                .chain(
+                    self.cmds[chorus + 1..start]
+                        .iter()
+                        .filter_map(|cmd| match cmd {
+                            Cmd::DeclareVar(v) => Some(*v),
+                            _ => None,
+                        }),
+                )
+                .collect();
+            let section = Function::Section(k + 1, vars);
+            let mut cmds = vec![Cmd::CallNoReturn(section.clone())];
+            if k > 0 {
+                cmds.push(done.clone());
+            }
+            cmds.push(Cmd::DeclareFn(section));
+            self.splice_cmds(start..start, cmds);
+        }
+        starts.len()
+    }
+    /// `output` with a comment before the commands of each Brainfuck
+    /// instruction that shows where in `bf_src` it came from, such as
+    /// `# +++` for three `+` merged into one addition. `bf_src` must be the
+    /// source the compiler was created from.
+    pub fn annotate_with_bf_source(
+        &self,
+        bf_src: &str,
+        opts: &CompilerOptions,
+    ) -> Result<String, CompilerError> {
+        self.verify_bracket_balance()?;
+        let mut res = String::new();
+        let mut level = 0i64;
+        let mut annotated = 0;
+        for (i, cmd) in self.cmds.iter().enumerate() {
+            while self
+                .instr_starts
+                .get(annotated)
+                .is_some_and(|&start| start <= i)
+            {
+                // a `]` is shown at the level of the loop's end
+                let mut comment_level = match cmd {
+                    Cmd::EndIf | Cmd::EndWhile => level - 1,
+                    _ => level,
+                };
+                let comment = Cmd::Comment(self.bf_source_of(annotated, bf_src));
+                res.push_str(&Self::format_cmd(&comment, &mut comment_level, opts)?);
+                annotated += 1;
+            }
+            res.push_str(&Self::format_cmd(cmd, &mut level, opts)?);
+        }
+        Ok(res)
+    }
+    /// The source text of the instructions that `instrs[index]` was made
+    /// from, with assertions shown in full
+    fn bf_source_of(&self, index: usize, bf_src: &str) -> String {
+        let origin = self.origins[index];
+        let end = self.origins.get(index + 1).copied().unwrap_or(usize::MAX);
+        let source_char = |position: usize| {
+            let offset = *self.source_offsets.get(position)?;
+            bf_src.get(offset..)?.chars().next()
+        };
+        let len = match self.instrs[index] {
+            BfInstruction::ClearData => 3,
+            BfInstruction::AddData(_) => (origin..end)
+                .take_while(|&p| matches!(source_char(p), Some('+' | '-')))
+                .count(),
+            _ => 1,
+        };
+        let mut res = String::new();
+        for position in origin..(origin + len).min(end) {
+            let Some(offset) = self.source_offsets.get(position) else {
+                break;
+            };
+            let text = bf_src.get(*offset..).unwrap_or_default();
+            match text.chars().next() {
+                Some(';') => res.push_str(text.lines().next().unwrap_or_default().trim_end()),
+                Some(c) => res.push(c),
+                None => {}
+            }
+        }
+        res
+    }
+    /// `output` compressed with gzip
+    #[cfg(feature = "compression")]
+    pub fn compress_output(&self, opts: &CompilerOptions) -> Result<Vec<u8>, CompilerError> {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write as _;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(self.output(opts)?.as_bytes())?;
+        Ok(encoder.finish()?)
+    }
+    /// `output` colored with ANSI escape codes for display in a terminal
+    pub fn output_with_syntax_highlighting(
+        &self,
+        opts: &CompilerOptions,
+    ) -> Result<String, CompilerError> {
+        let mut res = String::new();
+        for line in self.lines(opts) {
+            res.push_str(&Highlighter.highlight_line(&line?));
+            res.push('\n');
+        }
+        Ok(res)
+    }
+    /// The lines of `output`, without their line terminators, formatted one
+    /// command at a time. Iteration ends after the first error.
+    pub fn lines<'a>(
+        &'a self,
+        opts: &'a CompilerOptions,
+    ) -> impl Iterator<Item = Result<String, CompilerError>> + 'a {
+        let mut cmds = self.cmds.iter();
+        let mut pending = VecDeque::new();
+        let mut level = 0i64;
+        let mut unbalanced = self.verify_bracket_balance().err();
+        let mut done = false;
+        iter::from_fn(move || loop {
+            if let Some(err) = unbalanced.take() {
+                done = true;
+                return Some(Err(err));
+            }
+            if let Some(line) = pending.pop_front() {
+                return Some(Ok(line));
+            }
+            if done {
+                return None;
+            }
+            let staged = match cmds.next() {
+                Some(cmd) => Self::format_cmd(cmd, &mut level, opts),
+                None => return None,
+            };
+            match staged {
+                Ok(text) => pending.extend(text.lines().map(str::to_owned)),
+                Err(err) => {
+                    done = true;
+                    return Some(Err(err));
+                }
+            }
+        })
+    }
+    /// Checks that every `StartCond` is closed by exactly one `EndIf` or
+    /// `EndWhile`, without generating any output
+    pub fn verify_bracket_balance(&self) -> Result<(), CompilerError> {
+        let mut open = Vec::new();
+        for (i, cmd) in self.cmds.iter().enumerate() {
+            match cmd {
+                Cmd::StartCond(_) => open.push(i),
+                Cmd::EndIf | Cmd::EndWhile => {
+                    open.pop()
+                        .ok_or(CompilerError::UnbalancedBrackets { position: i })?;
+                }
+                _ => {}
+            }
+        }
+        match open.pop() {
+            Some(position) => Err(CompilerError::UnbalancedBrackets { position }),
+            None => Ok(()),
+        }
+    }
+    /// Formats one command at the nesting `level`, updating it. Each command
+    /// is staged separately so that a failed write never leaves a partially
+    /// emitted command behind. Brackets must already be balanced.
+    fn format_cmd(
+        cmd: &Cmd,
+        level: &mut i64,
+        opts: &CompilerOptions,
+    ) -> Result<String, CompilerError> {
+        if let Cmd::Assign(Var::Pointer | Var::Zero, Expr::Literal(Literal::NegativeInt(_))) = cmd {
+            return Err(CompilerError::NegativeLiteral);
+        }
+        if let Cmd::Assign(_, Expr::Literal(literal)) | Cmd::Return(Expr::Literal(literal)) = cmd {
+            if literal.has_unprintable_char() {
+                return Err(CompilerError::UnprintableLiteral);
+            }
+        }
+        let depth = match cmd {
+            Cmd::EndIf | Cmd::EndWhile => *level - 1,
+            _ => *level,
+        };
+        let mut staged = String::new();
+        for _ in 0..depth * opts.indent {
+            write!(staged, " ")?;
+        }
+        cmd.emit(&mut staged, opts.target)?;
+        *level = depth;
+        if let Cmd::StartCond(_) = cmd {
+            *level += 1;
+        }
+        Ok(staged)
+    }
+    /// Numbers of places in the generated program that read and write the
+    /// tape, in that order
+    pub fn count_tape_accesses(&self) -> (usize, usize) {
+        let mut reads = 0;
+        let mut writes = 0;
+        for cmd in &self.cmds {
+            match cmd {
+                Cmd::Assign(_, Expr::ArrayAccess(Var::Tape, _))
+                | Cmd::Return(Expr::ArrayAccess(Var::Tape, _))
+                | Cmd::StartCond(Expr::ArrayAccess(Var::Tape, _)) => reads += 1,
+                Cmd::Call(Function::ArrayReplace(Var::Tape, _, _), _) => writes += 1,
+                _ => {}
+            }
+        }
+        (reads, writes)
+    }
+    /// Total number of loop iterations the program performs, if every loop
+    /// runs a statically known number of times (such as a cell counted down
+    /// from a known constant). Loops nested in other loops are never known.
+    pub fn count_loop_iterations_bound(&self) -> Option<u64> {
+        BfParser::check_balance(&self.instrs).ok()?;
+        let iterations = opt::loop_iterations(&self.instrs);
+        let mut total = 0u64;
+        for (instr, iterations) in self.instrs.iter().zip(iterations) {
+            if *instr == BfInstruction::LoopStart {
+                total = total.checked_add(iterations?)?;
+            }
+        }
+        Some(total)
+    }
+    /// SHA-256 hash of the Brainfuck source's commands, which stays the same
+    /// when only comments change
+    #[cfg(feature = "hash")]
+    pub fn input_hash(&self) -> [u8; 32] {
+        self.input_hash
+    }
+    /// Splits the program into one part per top-level loop, together with
+    /// the code before it, followed by any code after the last loop. Each
+    /// part is compiled on its own with `opts`, including the preamble, and
+    /// runs from an empty tape. Where it is known, the pointer starts where
+    /// it would be when the part begins in the whole program.
+    pub fn split_by_loop(&self, opts: &CompilerOptions) -> Vec<Compiler> {
+        let balanced = opt::balanced_loops(&self.instrs);
+        // each part with the pointer's offset from its start in the program
+        let mut parts = Vec::new();
+        let mut start = 0;
+        let mut start_offset = Some(0i64);
+        let mut offset = Some(0i64);
+        let mut depth = 0usize;
+        for (i, instr) in self.instrs.iter().enumerate() {
+            match instr {
+                BfInstruction::IncPointer if depth == 0 => offset = offset.map(|o| o + 1),
+                BfInstruction::DecPointer if depth == 0 => offset = offset.map(|o| o - 1),
+                BfInstruction::LoopStart => {
+                    if depth == 0 && !balanced[i] {
+                        offset = None;
+                    }
+                    depth += 1;
+                }
+                BfInstruction::LoopEnd => {
+                    depth = depth.saturating_sub(1);
+                    if depth == 0 {
+                        parts.push((start..i + 1, start_offset));
+                        start = i + 1;
+                        start_offset = offset;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if start < self.instrs.len() {
+            parts.push((start..self.instrs.len(), start_offset));
+        }
+        parts
+            .into_iter()
+            .map(|(range, offset)| {
+                let pointer_start = offset
+                    .and_then(|o| usize::try_from(opts.pointer_start as i64 + o).ok())
+                    .unwrap_or(opts.pointer_start);
+                let opts = CompilerOptions {
+                    pointer_start,
+                    ..opts.clone()
+                };
+                let instrs = &self.instrs[range.clone()];
+                let program: String = instrs.iter().map(|instr| instr.to_string()).collect();
+                let mut compiler =
+                    Self::from_instructions(&program, instrs, &self.origins[range], &opts);
+                compiler.source_offsets = self.source_offsets.clone();
+                compiler.optimize_cmds(&opts);
+                compiler
+            })
+            .collect()
+    }
+    /// Whether the program contains a `.` command
+    pub fn produces_output(&self) -> bool {
+        self.instrs.contains(&BfInstruction::Output)
+    }
+    /// Likely mistakes in the compiled instructions, in the order of their
+    /// positions, with warnings about the whole program last
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+    /// Tape cells whose value never changes once the program looks at it,
+    /// by index, see `opt::constant_cells`
+    pub fn compute_constant_cells(&self) -> HashMap<usize, u8> {
+        opt::constant_cells(&self.instrs, self.pointer_start)
+    }
+    /// Minimum number of tape cells the program uses, found by following the
+    /// pointer through the chorus until it is moved by conditional code
+    pub fn tape_cell_count_lower_bound(&self) -> usize {
+        let mut pointer: Option<i64> = None;
+        let mut max = -1i64;
+        let mut level = 0usize;
+        let mut moved_in_cond = false;
+        for cmd in &self.cmds {
+            match cmd {
+                Cmd::StartCond(_) => level += 1,
+                Cmd::EndIf | Cmd::EndWhile => {
+                    level = level.saturating_sub(1);
+                    if level == 0 && moved_in_cond {
+                        pointer = None;
+                        moved_in_cond = false;
+                    }
+                }
+                Cmd::Assign(Var::Pointer, _) | Cmd::Call(_, Var::Pointer) if level > 0 => {
+                    moved_in_cond = true;
+                }
+                Cmd::Assign(Var::Pointer, e) => {
+                    pointer = match e {
+                        Expr::Literal(Literal::Int(n)) => Some(*n as i64),
+                        Expr::Inc(Var::Pointer) => pointer.map(|p| p + 1),
+                        Expr::Dec(Var::Pointer) => pointer.map(|p| p - 1),
+                        Expr::Add(Var::Pointer, Literal::Int(n)) => pointer.map(|p| p + *n as i64),
+                        _ => None,
+                    };
+                    max = max.max(pointer.unwrap_or(-1));
+                }
+                Cmd::Call(_, Var::Pointer) => pointer = None,
+                _ => {}
+            }
+        }
+        (max + 1) as usize
+    }
+    /// The largest value the pointer has when any instruction runs, if it
+    /// can be proven by following `>` and `<` through the program. Loops
+    /// that leave the pointer where they found it are followed into, but
+    /// any other loop moves the pointer by an unknown amount, which makes
+    /// the maximum unknown.
+    pub fn max_pointer_value(&self) -> Option<usize> {
+        let positions = opt::pointer_positions(&self.instrs, self.pointer_start);
+        let mut max = self.pointer_start as i64;
+        for pos in positions {
+            max = max.max(pos?);
+        }
+        usize::try_from(max).ok()
+    }
+    pub fn stats(&self) -> CompilerStats {
+        let (tape_reads, tape_writes) = self.count_tape_accesses();
+        CompilerStats {
+            tape_reads,
+            tape_writes,
+            loop_iterations_bound: self.count_loop_iterations_bound(),
+            unique_functions: self.number_of_unique_functions(),
+            complexity: self.estimate_runtime_complexity(),
+        }
+    }
+    /// Coarse estimate of how the running time grows with the cell values
+    /// that control the loops. A loop that moves the pointer back to where
+    /// it started and changes its cell by a fixed amount each iteration
+    /// counts as linear, and nesting such loops multiplies their cost. A loop
+    /// that doubles the cell of an inner loop every iteration is exponential.
+    /// Programs whose loops all run a known number of times are constant.
+    pub fn estimate_runtime_complexity(&self) -> BigO {
+        if self.count_loop_iterations_bound().is_some() {
+            return BigO::Constant;
+        }
+        let mut degree = 0;
+        let mut i = 0;
+        while i < self.instrs.len() {
+            if self.instrs[i] == BfInstruction::LoopStart {
+                let Some((loop_degree, end, _)) = loop_degree(&self.instrs, i) else {
+                    return BigO::Unknown;
+                };
+                if (i..end).any(|j| {
+                    self.instrs[j] == BfInstruction::LoopStart
+                        && doubles_inner_loop_cell(&self.instrs, j)
+                }) {
+                    return BigO::Exponential;
+                }
+                degree = degree.max(loop_degree);
+                i = end;
+            }
+            i += 1;
+        }
+        match degree {
+            0 => BigO::Constant,
+            1 => BigO::Linear,
+            2 => BigO::Quadratic,
+            degree => BigO::Polynomial(degree),
+        }
+    }
+    /// Number of lines of the output that commands of each kind take up, such
+    /// as 2 for every verse header and 1 for every `EndWhile`. Kinds that do
+    /// not occur in the program are left out.
+    pub fn measure_output_size_distribution(&self) -> HashMap<CmdKind, usize> {
+        let mut lines = HashMap::new();
+        let mut text = String::new();
+        for cmd in &self.cmds {
+            text.clear();
+            // every target writes each command on the same number of lines
+            cmd.emit(&mut text, RickrollVersion::default())
+                .expect("writing to a String cannot fail");
+            *lines.entry(cmd.kind()).or_insert(0) += text.lines().count();
+        }
+        lines
+    }
+    /// Number of differently named verses the program defines, which is the
+    /// number of `[Verse ...]` blocks in the output
+    pub fn number_of_unique_functions(&self) -> usize {
+        self.cmds
+            .iter()
+            .filter_map(|cmd| match cmd {
+                Cmd::DeclareFn(f) => Some(f.name()),
+                _ => None,
+            })
+            .collect::<HashSet<_>>()
+            .len()
+    }
+    /// Features an interpreter needs to run the program, going by the
+    /// functions it calls. Verses that are defined but never called, such
+    /// as `CharToInt` in programs without input, do not count.
+    pub fn required_features(&self) -> BTreeSet<Feature> {
+        let mut features = BTreeSet::new();
+        for cmd in &self.cmds {
+            match cmd {
+                Cmd::Call(f, _) | Cmd::CallNoReturn(f) => {
+                    features.insert(f.feature());
+                }
+                Cmd::Assign(_, Expr::ArrayAccess(_, _)) => {
+                    features.insert(Feature::Arrays);
+                }
+                _ => {}
+            }
+        }
+        features
+    }
+    /// Variables that are referenced other than by their declaration and
+    /// initialization
+    pub fn used_vars(&self) -> HashSet<Var> {
+        let mut used = HashSet::new();
+        for cmd in &self.cmds {
+            match cmd {
+                Cmd::DeclareVar(_) | Cmd::Assign(_, Expr::Literal(_)) => {}
+                Cmd::Assign(v, e) => {
+                    used.insert(*v);
+                    used.extend(e.vars());
+                }
+                Cmd::Call(f, v) => {
+                    used.insert(*v);
+                    used.extend(f.vars());
+                }
+                Cmd::DeclareFn(f) | Cmd::CallNoReturn(f) => used.extend(f.vars()),
+                Cmd::Return(e) | Cmd::StartCond(e) => used.extend(e.vars()),
+                Cmd::Halt => {
+                    used.insert(Var::Zero);
+                }
+                Cmd::DeclareChorus(..)
+                | Cmd::EndIf
+                | Cmd::EndWhile
+                | Cmd::Trace(_)
+                | Cmd::Comment(_) => {}
+            }
+        }
+        used
+    }
+    /// Removes the declaration and initialization of variables that are
+    /// otherwise never used, such as `Buffer` in programs without input
+    pub fn eliminate_unused_vars(&mut self) {
+        let used = self.used_vars();
+        self.retain_cmds(|_, cmd| match cmd {
+            Cmd::DeclareVar(v) | Cmd::Assign(v, Expr::Literal(_)) => used.contains(v),
+            _ => true,
+        });
+    }
+    /// Lets scratch variables declared in the same verse or chorus share
+    /// one variable when their values are never needed at the same time,
+    /// and removes the declarations of the variables renamed away. A
+    /// variable is live from its first use to its last one, and during the
+    /// whole of any loop it is used in, as the next iteration may read what
+    /// it held at the end of the previous one. Only variables that are
+    /// first assigned a value outside of any condition that ends before
+    /// their last use take part, and parameters never do. Returns the
+    /// number of variables renamed.
+    pub fn reuse_scratch_vars(&mut self) -> usize {
+        let headers: Vec<usize> = (0..self.cmds.len())
+            .filter(|&i| matches!(self.cmds[i], Cmd::DeclareFn(_) | Cmd::DeclareChorus(..)))
+            .collect();
+        let mut dropped = HashSet::new();
+        for (n, &decl) in headers.iter().enumerate() {
+            let end = headers.get(n + 1).copied().unwrap_or(self.cmds.len());
+            for (from, to) in self.scratch_var_renames(decl, end) {
+                for i in decl + 1..end {
+                    if self.cmds[i] == Cmd::DeclareVar(from) {
+                        dropped.insert(i);
+                    } else {
+                        self.cmds[i].rename_var(from, to);
+                    }
+                }
+            }
+        }
+        self.retain_cmds(|i, _| !dropped.contains(&i));
+        dropped.len()
+    }
+    /// The renames `reuse_scratch_vars` makes in the verse or chorus
+    /// declared at `decl` and ending at `end`, in the order to make them
+    fn scratch_var_renames(&self, decl: usize, end: usize) -> Vec<(Var, Var)> {
+        let params = self.cmds[decl].vars();
+        // the conditions and loops of the body, from their start to their end
+        let mut blocks = Vec::new();
+        let mut loops = Vec::new();
+        let mut open = Vec::new();
+        for i in decl + 1..end {
+            match self.cmds[i] {
+                Cmd::StartCond(_) => open.push(i),
+                Cmd::EndIf | Cmd::EndWhile => {
+                    let Some(start) = open.pop() else { continue };
+                    blocks.push(start..=i);
+                    if self.cmds[i] == Cmd::EndWhile {
+                        loops.push(start..=i);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // the variables that take part, with the commands they are live in
+        let mut live = Vec::new();
+        for i in decl + 1..end {
+            let Cmd::DeclareVar(v) = self.cmds[i] else {
+                continue;
+            };
+            if !v.is_scratch() || params.contains(&v) {
+                continue;
+            }
+            let uses: Vec<usize> = (decl + 1..end)
+                .filter(|&j| !matches!(self.cmds[j], Cmd::DeclareVar(_)))
+                .filter(|&j| self.cmds[j].vars().contains(&v))
+                .collect();
+            let (Some(&first), Some(&last)) = (uses.first(), uses.last()) else {
+                continue;
+            };
+            let skipped = blocks
+                .iter()
+                .any(|block| block.contains(&first) && !block.contains(&last));
+            if !self.cmds[first].overwrites(v) || skipped {
+                continue;
+            }
+            let (mut start, mut stop) = (first, last);
+            for range in &loops {
+                if uses.iter().any(|j| range.contains(j)) {
+                    start = start.min(*range.start());
+                    stop = stop.max(*range.end());
+                }
+            }
+            live.push((v, start..=stop));
+        }
+        live.sort_by_key(|(_, range)| *range.start());
+        // the variables that are kept, with the ranges they are live in
+        let mut kept: Vec<(Var, Vec<RangeInclusive<usize>>)> = Vec::new();
+        let mut renames = Vec::new();
+        for (v, range) in live {
+            let free = kept.iter_mut().find(|(_, ranges)| {
+                ranges
+                    .iter()
+                    .all(|r| r.end() < range.start() || r.start() > range.end())
+            });
+            match free {
+                Some((to, ranges)) => {
+                    renames.push((v, *to));
+                    ranges.push(range);
+                }
+                None => kept.push((v, vec![range])),
+            }
+        }
+        renames
+    }
+    /// Inlines verses that another verse calls right before it returns, as
+    /// in `Never gonna run PutLine and desert Temp` followed by the caller's
+    /// return, and removes the inlined verses once nothing calls them. A
+    /// call is only inlined if the arguments have the same names as the
+    /// parameters, the called verse returns only at its end and declares
+    /// none of the caller's variables, and the caller's return value does
+    /// not depend on variables the called verse changes. Returns the number
+    /// of calls inlined.
+    pub fn optimize_tail_calls(&mut self) -> usize {
+        // removing a verse can leave another one uncalled, so they are
+        // removed in a fixed order to keep the output deterministic
+        let mut inlined = BTreeSet::new();
+        let mut count = 0;
+        // later verses first, so that the bodies found earlier stay in place
+        for (decl, end) in self.verse_bodies().into_iter().rev() {
+            // the declaration, the call and the return
+            if end < decl + 3 {
+                continue;
+            }
+            let (Cmd::DeclareFn(caller), [Cmd::CallNoReturn(callee), Cmd::Return(ret)]) =
+                (&self.cmds[decl], &self.cmds[end - 2..end])
+            else {
+                continue;
+            };
+            let name = callee.name();
+            if let Some(body) = self.tail_call_body(decl, end, &caller.name(), callee, ret) {
+                self.splice_cmds(end - 2..end, body);
+                inlined.insert(name);
+                count += 1;
+            }
+        }
+        for name in inlined {
+            let called = self.cmds.iter().any(|cmd| match cmd {
+                Cmd::Call(f, _) | Cmd::CallNoReturn(f) => f.name() == name,
+                _ => false,
+            });
+            let body = self.verse_bodies().into_iter().find(
+                |&(decl, _)| matches!(&self.cmds[decl], Cmd::DeclareFn(f) if f.name() == name),
+            );
+            if let (false, Some((decl, end))) = (called, body) {
+                self.splice_cmds(decl..end, Vec::new());
+            }
+        }
+        count
+    }
+    /// The range of each verse, from its declaration to the start of the
+    /// next verse or the chorus
+    fn verse_bodies(&self) -> Vec<(usize, usize)> {
+        let mut bodies: Vec<(usize, usize)> = Vec::new();
+        let mut open = None;
+        for (i, cmd) in self.cmds.iter().enumerate() {
+            if matches!(cmd, Cmd::DeclareFn(_) | Cmd::DeclareChorus(..)) {
+                bodies.extend(open.take().map(|decl| (decl, i)));
+                if let Cmd::DeclareFn(_) = cmd {
+                    open = Some(i);
+                }
+            }
+        }
+        bodies.extend(open.map(|decl| (decl, self.cmds.len())));
+        bodies
+    }
+    /// The commands replacing a tail call of `callee` and the following
+    /// `Return(ret)` at the end of the verse in `decl..end`, if the call can
+    /// be inlined
+    fn tail_call_body(
+        &self,
+        decl: usize,
+        end: usize,
+        caller: &str,
+        callee: &Function,
+        ret: &Expr,
+    ) -> Option<Vec<Cmd>> {
+        if callee.name() == caller {
+            return None;
+        }
+        let (callee_decl, callee_end) = self.verse_bodies().into_iter().find(
+            |&(d, _)| matches!(&self.cmds[d], Cmd::DeclareFn(f) if f.name() == callee.name()),
+        )?;
+        let Cmd::DeclareFn(params) = &self.cmds[callee_decl] else {
+            unreachable!("verse bodies start after a declaration");
+        };
+        let body = &self.cmds[callee_decl + 1..callee_end];
+        let (Cmd::Return(_), rest) = body.split_last()? else {
+            return None;
+        };
+        if params.vars() != callee.vars()
+            || rest
+                .iter()
+                .any(|cmd| matches!(cmd, Cmd::Return(_) | Cmd::Halt))
+        {
+            return None;
+        }
+        let caller_vars: HashSet<Var> = self.cmds[decl..end]
+            .iter()
+            .flat_map(|cmd| match cmd {
+                Cmd::DeclareFn(f) => f.vars(),
+                Cmd::DeclareVar(v) => vec![*v],
+                _ => Vec::new(),
+            })
+            .collect();
+        let written: HashSet<Var> = rest
+            .iter()
+            .filter_map(|cmd| match cmd {
+                Cmd::DeclareVar(v) | Cmd::Assign(v, _) | Cmd::Call(_, v) => Some(*v),
+                _ => None,
+            })
+            .collect();
+        let redeclares = rest
+            .iter()
+            .any(|cmd| matches!(cmd, Cmd::DeclareVar(v) if caller_vars.contains(v)));
+        if redeclares || ret.vars().iter().any(|v| written.contains(v)) {
+            return None;
+        }
+        let mut res = rest.to_vec();
+        res.push(Cmd::Return(ret.clone()));
+        Some(res)
+    }
+    /// Removes writes to the current cell that are overwritten before the
+    /// tape is read again or the pointer moves, such as an addition followed
+    /// by a clear or an input. A write only counts as overwritten by a later
+    /// one at the same or an outer nesting level, as the later write could
+    /// otherwise be skipped.
+    pub fn eliminate_dead_tape_writes(&mut self) {
+        let mut dead = vec![false; self.cmds.len()];
+        // the unread write at each open nesting level, innermost last
+        let mut pending: Vec<Option<usize>> = vec![None];
+        let cell = self.cell();
+        for (i, cmd) in self.cmds.iter().enumerate() {
+            let reads = match cmd {
+                Cmd::Call(Function::ArrayReplace(Var::Tape, index, v), Var::Tape)
+                    if *index == cell && *v != Var::Tape =>
+                {
+                    let current = pending.last_mut().expect("there is always a current level");
+                    if let Some(j) = current.replace(i) {
+                        dead[j] = true;
+                    }
+                    continue;
+                }
+                Cmd::DeclareFn(_) | Cmd::DeclareChorus(..) => {
+                    pending = vec![None];
+                    continue;
+                }
+                Cmd::Assign(Var::Pointer, _) | Cmd::Call(_, Var::Pointer) => true,
+                Cmd::Assign(_, e) | Cmd::StartCond(e) | Cmd::Return(e) => {
+                    e.vars().contains(&Var::Tape)
+                }
+                Cmd::Call(f, _) | Cmd::CallNoReturn(f) => f.vars().contains(&Var::Tape),
+                Cmd::DeclareVar(_)
+                | Cmd::EndIf
+                | Cmd::EndWhile
+                | Cmd::Halt
+                | Cmd::Trace(_)
+                | Cmd::Comment(_) => false,
+            };
+            if reads {
+                pending.iter_mut().for_each(|write| *write = None);
+            }
+            match cmd {
+                Cmd::StartCond(_) => pending.push(None),
+                Cmd::EndIf | Cmd::EndWhile if pending.len() > 1 => {
+                    pending.pop();
+                }
+                _ => {}
+            }
+        }
+        self.retain_cmds(|i, _| !dead[i]);
+    }
+    /// Replaces reads of the current cell with its value where the pointer
+    /// is known and `compute_constant_cells` found the cell to be constant,
+    /// which is only where the program looks at the cell: printing it,
+    /// testing it in a loop condition or asserting on it. Printing a
+    /// replaced cell no longer calls `IntToChar`. Returns the number of
+    /// reads replaced.
+    pub fn bake_constants(&mut self) -> usize {
+        // the analysis assumes that the tape starts out zero
+        if !self.tape_init.is_empty() {
+            return 0;
+        }
+        let constants = self.compute_constant_cells();
+        let positions = opt::pointer_positions(&self.instrs, self.pointer_start);
+        let load = Expr::ArrayAccess(Var::Tape, self.cell());
+        let mut count = 0;
+        for (i, (instr, pos)) in self.instrs.iter().zip(positions).enumerate() {
+            if !matches!(
+                instr,
+                BfInstruction::Output
+                    | BfInstruction::LoopStart
+                    | BfInstruction::LoopEnd
+                    | BfInstruction::AssertData(_)
+                    | BfInstruction::Sleep
+            ) {
+                continue;
+            }
+            let Some(&value) = pos
+                .and_then(|p| usize::try_from(p).ok())
+                .and_then(|p| constants.get(&p))
+            else {
+                continue;
+            };
+            let start = self.instr_starts[i];
+            let end = self
+                .instr_starts
+                .get(i + 1)
+                .copied()
+                .unwrap_or(self.cmds.len());
+            // the last instruction's commands may be followed by verses
+            let cmds = self.cmds[start..end]
+                .iter_mut()
+                .take_while(|cmd| !matches!(cmd, Cmd::DeclareFn(_)));
+            for cmd in cmds {
+                match cmd {
+                    Cmd::Assign(_, e @ Expr::ArrayAccess(Var::Tape, _)) if *e == load => {
+                        *e = Expr::Literal(Literal::Int(value));
+                        count += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let convert = Cmd::Call(Function::IntToChar(Var::Temp), Var::Temp);
+        self.apply_pattern_replacement(
+            &[CmdPattern::AnyAssign, CmdPattern::SpecificCmd(convert)],
+            |cmds| match cmds {
+                [Cmd::Assign(Var::Temp, Expr::Literal(Literal::Int(value))), _] => {
+                    vec![Cmd::Assign(
+                        Var::Temp,
+                        Expr::Literal(Literal::Char(int_to_char(*value as i64))),
+                    )]
+                }
+                _ => cmds.to_vec(),
+            },
+        );
+        count
+    }
+    /// Replaces each loop that `opt::loop_iterations` finds to run a known
+    /// number of times, which is at least 1 and at most `max_iterations`,
+    /// with that many copies of its body, as in `+++[>++<-]`. Trace commands
+    /// of the brackets are kept where the loop would run them. Returns the
+    /// number of loops unrolled.
+    pub fn unroll_small_loops(&mut self, max_iterations: usize) -> usize {
+        // the analysis assumes that the tape starts out zero
+        if !self.tape_init.is_empty() {
+            return 0;
+        }
+        let iterations = opt::loop_iterations(&self.instrs);
+        let mut ends = vec![0; self.instrs.len()];
+        let mut starts = Vec::new();
+        for (i, instr) in self.instrs.iter().enumerate() {
+            match instr {
+                BfInstruction::LoopStart => starts.push(i),
+                BfInstruction::LoopEnd => {
+                    if let Some(start) = starts.pop() {
+                        ends[start] = i;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut count = 0;
+        // the last loop first, so that the commands of earlier ones stay in
+        // place
+        for (start, k) in iterations.iter().enumerate().rev() {
+            let Some(k) = k.and_then(|k| usize::try_from(k).ok()) else {
+                continue;
+            };
+            if k == 0 || k > max_iterations {
+                continue;
+            }
+            let end = ends[start];
+            let cmds_of = |instr: usize| {
+                let next = self.instr_starts.get(instr + 1).copied();
+                self.instr_starts[instr]..next.unwrap_or(self.cmds.len())
+            };
+            let (open, body, close) = (cmds_of(start), cmds_of(start + 1), cmds_of(end));
+            let body = body.start..close.start;
+            // outlined loops are a single call
+            if !self.cmds[open.clone()]
+                .iter()
+                .any(|cmd| matches!(cmd, Cmd::StartCond(_)))
+                || !self.cmds[close.clone()].contains(&Cmd::EndWhile)
+            {
+                continue;
+            }
+            let traces = |range: Range<usize>| {
+                self.cmds[range]
+                    .iter()
+                    .filter(|cmd| matches!(cmd, Cmd::Trace(_)))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            let mut unrolled = traces(open.clone());
+            let close_traces = traces(close.clone());
+            for _ in 0..k {
+                unrolled.extend_from_slice(&self.cmds[body.clone()]);
+                unrolled.extend_from_slice(&close_traces);
+            }
+            self.splice_cmds(open.start..close.end, unrolled);
+            count += 1;
+        }
+        count
+    }
+    /// Rewrites the commands into a canonical form, so that programs that
+    /// only differ in trivial ways, such as the order in which variables are
+    /// declared, end up with the same commands. Commands that change
+    /// nothing are removed, declarations that follow a use of their
+    /// variable are moved up to the other declarations at the start of the
+    /// verse or chorus, and runs of declarations and of literal
+    /// initializations of different variables are sorted by variable.
+    pub fn normalize(&mut self) {
+        self.retain_cmds(|_, cmd| !cmd.is_noop());
+        while let Some((decl, dest)) = self.late_declaration() {
+            let cmd = self.cmds[decl].clone();
+            self.retain_cmds(|i, _| i != decl);
+            self.splice_cmds(dest..dest, vec![cmd]);
+        }
+        self.sort_runs(|cmd| match cmd {
+            Cmd::DeclareVar(v) => Some(*v),
+            _ => None,
+        });
+        self.sort_runs(|cmd| match cmd {
+            Cmd::Assign(v, Expr::Literal(_)) => Some(*v),
+            _ => None,
+        });
+    }
+    /// The index of the first declaration of a variable that was already
+    /// used in the same verse or chorus, together with the index after the
+    /// declarations at the start of that verse or chorus. Redeclared
+    /// parameters are left alone.
+    fn late_declaration(&self) -> Option<(usize, usize)> {
+        let mut body_start = 0;
+        let mut params = Vec::new();
+        let mut used = HashSet::new();
+        for (i, cmd) in self.cmds.iter().enumerate() {
+            match cmd {
+                Cmd::DeclareFn(_) | Cmd::DeclareChorus(..) => {
+                    body_start = i + 1;
+                    params = cmd.vars();
+                    used.clear();
+                }
+                Cmd::DeclareVar(v) if used.contains(v) && !params.contains(v) => {
+                    let decls = self.cmds[body_start..]
+                        .iter()
+                        .take_while(|cmd| matches!(cmd, Cmd::DeclareVar(_)))
+                        .count();
+                    return Some((i, body_start + decls));
+                }
+                _ => used.extend(cmd.vars()),
+            }
+        }
+        None
+    }
+    /// Sorts each run of consecutive commands for which `var_of` returns
+    /// different variables by that variable
+    fn sort_runs(&mut self, var_of: fn(&Cmd) -> Option<Var>) {
+        let mut i = 0;
+        while i < self.cmds.len() {
+            let mut run = Vec::new();
+            while let Some(v) = self.cmds.get(i + run.len()).and_then(var_of) {
+                if run.contains(&v) {
+                    break;
+                }
+                run.push(v);
+            }
+            let len = run.len();
+            if !run.is_sorted() {
+                let mut sorted = self.cmds[i..i + len].to_vec();
+                sorted.sort_by_key(var_of);
+                self.splice_cmds(i..i + len, sorted);
+            }
+            i += len.max(1);
+        }
+    }
+    /// Keeps the commands for which `keep`, given their index and the
+    /// command, returns true
+    fn retain_cmds(&mut self, mut keep: impl FnMut(usize, &Cmd) -> bool) {
+        // the number of kept commands before each index
+        let mut kept_before = Vec::with_capacity(self.cmds.len() + 1);
+        let mut kept = 0;
+        self.cmds.retain(|cmd| {
+            let i = kept_before.len();
+            kept_before.push(kept);
+            let keep = keep(i, cmd);
+            kept += usize::from(keep);
+            keep
+        });
+        kept_before.push(kept);
+        for start in &mut self.instr_starts {
+            *start = kept_before[*start];
+        }
+    }
+    /// Replaces the commands in `range` with `replacement`. An instruction
+    /// whose commands start inside `range` starts at the same offset in
+    /// `replacement`, or right after it if `replacement` is shorter.
+    fn splice_cmds(&mut self, range: Range<usize>, replacement: Vec<Cmd>) {
+        let (removed, added) = (range.len(), replacement.len());
+        self.cmds.splice(range.clone(), replacement);
+        for start in &mut self.instr_starts {
+            if *start >= range.end {
+                *start = *start - removed + added;
+            } else if *start > range.start {
+                *start = range.start + (*start - range.start).min(added);
+            }
+        }
+    }
+    /// The generated commands, in the order they are emitted
+    pub fn cmds(&self) -> &[Cmd] {
+        &self.cmds
+    }
+    /// Replaces the command at `index` with any number of commands, which
+    /// deletes it if `replacement` is empty
+    pub fn replace_cmd_at(&mut self, index: usize, replacement: Vec<Cmd>) -> &mut Self {
+        assert!(
+            index < self.cmds.len(),
+            "replace_cmd_at: index {} is out of range for {} commands",
+            index,
+            self.cmds.len()
+        );
+        self.splice_cmds(index..index + 1, replacement);
+        self
+    }
+    /// Starting indices of the non-overlapping runs of commands that match
+    /// `pattern` element by element, searching from the start of the
+    /// program. An empty pattern matches nowhere.
+    pub fn find_pattern(&self, pattern: &[CmdPattern]) -> Vec<usize> {
+        let mut res = Vec::new();
+        if pattern.is_empty() {
+            return res;
+        }
+        let mut i = 0;
+        while i + pattern.len() <= self.cmds.len() {
+            let window = &self.cmds[i..i + pattern.len()];
+            if pattern.iter().zip(window).all(|(p, cmd)| p.matches(cmd)) {
+                res.push(i);
+                i += pattern.len();
+            } else {
+                i += 1;
+            }
+        }
+        res
+    }
+    /// Replaces each match of `pattern` that `find_pattern` finds with the
+    /// commands `replacement` returns for it, and returns the number of
+    /// matches
+    pub fn apply_pattern_replacement(
+        &mut self,
+        pattern: &[CmdPattern],
+        replacement: fn(&[Cmd]) -> Vec<Cmd>,
+    ) -> usize {
+        let matches = self.find_pattern(pattern);
+        assert!(
+            matches.windows(2).all(|w| w[0] + pattern.len() <= w[1]),
+            "apply_pattern_replacement: matches overlap"
+        );
+        // the last match first, so that the others keep their indices
+        for &i in matches.iter().rev() {
+            let new = replacement(&self.cmds[i..i + pattern.len()]);
+            self.splice_cmds(i..i + pattern.len(), new);
+        }
+        matches.len()
+    }
+    /// Prints groups of characters that are known at compile time with a
+    /// single `PutStr` of a string literal instead of one `PutChar` each, as
+    /// in `Hello, World!`. The characters of a group may be separated by
+    /// assignments, array operations and conditions on them, such as the
+    /// growing of the tape by `>`, but not by loops or anything else that
+    /// could print or stop the program. Returns the number of groups fused.
+    pub fn fuse_adjacent_putchar_calls(&mut self) -> usize {
+        // the index of each print of a known character, in groups, with the
+        // characters of each group
+        let mut groups: Vec<(Vec<usize>, String)> = Vec::new();
+        let mut end_of_last = None;
+        // whether `Pointer` indexes `Tape` at the last print
+        let mut pointer_in_bounds = false;
+        let mut i = 0;
+        while i + 1 < self.cmds.len() {
+            let c = match &self.cmds[i] {
+                Cmd::Assign(Var::Temp, Expr::Literal(Literal::Char(c)))
+                    if self.cmds[i + 1] == Cmd::CallNoReturn(Function::PutChar(Var::Temp))
+                        && self.overwrites_temp(i + 2) =>
+                {
+                    *c
+                }
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let joined = end_of_last
+                .and_then(|end| always_completes_silently(&self.cmds[end..i], pointer_in_bounds));
+            match (groups.last_mut(), joined) {
+                (Some((prints, text)), Some(in_bounds)) => {
+                    prints.push(i);
+                    text.push(c);
+                    pointer_in_bounds = in_bounds;
+                }
+                _ => {
+                    groups.push((vec![i], c.to_string()));
+                    pointer_in_bounds = self.pointer_in_bounds_before(i);
+                }
+            }
+            i += 2;
+            end_of_last = Some(i);
+        }
+        groups.retain(|(prints, _)| prints.len() > 1);
+        // the last print first, so that the others keep their indices
+        for (prints, text) in groups.iter().rev() {
+            let (&last, rest) = prints.split_last().expect("groups are not empty");
+            self.splice_cmds(
+                last..last + 2,
+                vec![
+                    Cmd::Assign(Var::Temp, Expr::Literal(Literal::Str(text.clone()))),
+                    Cmd::CallNoReturn(Function::PutStr(Var::Temp)),
+                ],
+            );
+            for &print in rest.iter().rev() {
+                self.splice_cmds(print..print + 2, Vec::new());
+            }
+        }
+        groups.len()
+    }
+    /// Whether `Pointer` is a valid index of `Tape` when the command at
+    /// `index` runs, because `Tape` was accessed at it or it was set to a
+    /// literal earlier in the same straight run of commands
+    fn pointer_in_bounds_before(&self, index: usize) -> bool {
+        for cmd in self.cmds[..index].iter().rev() {
+            match cmd {
+                Cmd::Assign(_, Expr::ArrayAccess(Var::Tape, Var::Pointer))
+                | Cmd::Call(Function::ArrayReplace(Var::Tape, Var::Pointer, _), Var::Tape)
+                | Cmd::Assign(Var::Pointer, Expr::Literal(Literal::Int(_))) => return true,
+                Cmd::Assign(_, _) | Cmd::Call(_, _) | Cmd::CallNoReturn(_) | Cmd::Comment(_)
+                    if !cmd.vars().contains(&Var::Pointer) && !cmd.vars().contains(&Var::Tape) => {}
+                _ => return false,
+            }
+        }
+        false
+    }
+    /// Whether `Temp` is assigned a new value, without reading the old one,
+    /// before anything from `index` on could read it
+    fn overwrites_temp(&self, index: usize) -> bool {
+        for cmd in &self.cmds[index..] {
+            match cmd {
+                Cmd::Assign(Var::Temp, e) => return !e.vars().contains(&Var::Temp),
+                Cmd::Call(f, Var::Temp) => return !f.vars().contains(&Var::Temp),
+                Cmd::Return(e) => return !e.vars().contains(&Var::Temp),
+                // the verse or chorus has ended
+                Cmd::DeclareFn(_) | Cmd::DeclareChorus(..) | Cmd::Halt => return true,
+                Cmd::Assign(_, _)
+                | Cmd::Call(_, _)
+                | Cmd::CallNoReturn(_)
+                | Cmd::Comment(_)
+                | Cmd::Trace(_)
+                    if !cmd.vars().contains(&Var::Temp) => {}
+                _ => return false,
+            }
+        }
+        // nothing reads it after the end of the program
+        true
+    }
+    /// Removes loads of the current cell into `Temp` right after `Temp` was
+    /// stored into it, as in `++` or `+[`
+    fn forward_tape_stores(&mut self) -> usize {
+        let store = Cmd::Call(
+            Function::ArrayReplace(Var::Tape, self.cell(), Var::Temp),
+            Var::Tape,
+        );
+        let load = Cmd::Assign(Var::Temp, Expr::ArrayAccess(Var::Tape, self.cell()));
+        self.apply_pattern_replacement(
+            &[
+                CmdPattern::SpecificCmd(store),
+                CmdPattern::SpecificCmd(load),
+            ],
+            |cmds| vec![cmds[0].clone()],
+        )
+    }
+    /// The generated program as Rust-like pseudocode
+    pub fn pretty_print_ir(&self) -> String {
+        pretty::pretty_print_cmds(&self.cmds)
+    }
+    pub fn strip_trace_commands(&mut self) {
+        self.retain_cmds(|_, cmd| !matches!(cmd, Cmd::Trace(_)));
+    }
+    pub fn diff_output(
+        &self,
+        other: &Compiler,
+        opts: &CompilerOptions,
+    ) -> Result<String, CompilerError> {
+        Ok(diff::unified_diff(
+            &self.output(opts)?,
+            &other.output(opts)?,
+        )?)
+    }
+    /// Whether `output` is the same as the contents of the file at
+    /// `snapshot_path`, for snapshot tests. When the `UPDATE_SNAPSHOTS`
+    /// environment variable is set, the file is overwritten with the output
+    /// instead, and the result is always `true`.
+    pub fn assert_output_matches_snapshot(
+        &self,
+        snapshot_path: &Path,
+        opts: &CompilerOptions,
+    ) -> Result<bool, CompilerError> {
+        let output = self.output(opts)?;
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::write(snapshot_path, output)?;
+            return Ok(true);
+        }
+        Ok(fs::read_to_string(snapshot_path)? == output)
+    }
+    fn define_char_to_int(&mut self) {
+        self.cmds
+            .push(Cmd::DeclareFn(Function::CharToInt(Var::Temp)));
+        for c in iter::once('\n').chain(' '..='~') {
+            self.cmds.push(Cmd::StartCond(Expr::IsEqualLiteral(
+                Var::Temp,
+                Literal::Char(c),
+            )));
+            self.cmds
+                .push(Cmd::Return(Expr::Literal(Literal::Int(c as u8))));
+            self.cmds.push(Cmd::EndIf);
+        }
+        self.cmds.push(Cmd::Return(Expr::Literal(Literal::Int(0))));
+    }
+    fn define_int_to_char(&mut self) {
+        self.cmds
+            .push(Cmd::DeclareFn(Function::IntToChar(Var::Temp)));
+        for i in iter::once(b'\n').chain(b' '..=b'~') {
+            self.cmds.push(Cmd::StartCond(Expr::IsEqualLiteral(
+                Var::Temp,
+                Literal::Int(i),
+            )));
+            self.cmds
+                .push(Cmd::Return(Expr::Literal(Literal::Char(i as char))));
+            self.cmds.push(Cmd::EndIf);
+        }
+        self.cmds
+            .push(Cmd::Return(Expr::Literal(Literal::Char('$'))));
+    }
+    /// Defines `PutInt`, which prints a sign if needed and then the digits
+    /// of its argument, recursing to print the higher ones first. Only used
+    /// by assertions, and needs `IntToChar`.
+    fn define_put_int(&mut self) {
+        self.cmds.push(Cmd::DeclareFn(Function::PutInt(Var::Temp)));
+        // the verse cannot see the chorus's variables
+        self.cmds.push(Cmd::DeclareVar(Var::Zero));
+        self.cmds.push(Cmd::DeclareVar(Var::Digit));
+        self.cmds
+            .push(Cmd::Assign(Var::Zero, Expr::Literal(Literal::Int(0))));
+        self.cmds
+            .push(Cmd::StartCond(Expr::IsLessVar(Var::Temp, Var::Zero)));
+        self.cmds
+            .push(Cmd::Assign(Var::Digit, Expr::Literal(Literal::Char('-'))));
+        self.cmds
+            .push(Cmd::CallNoReturn(Function::PutChar(Var::Digit)));
+        self.cmds.push(Cmd::Assign(Var::Temp, Expr::Neg(Var::Temp)));
+        self.cmds.push(Cmd::EndIf);
+        self.cmds.push(Cmd::Assign(
+            Var::Digit,
+            Expr::Mod(Var::Temp, Literal::Int(10)),
+        ));
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::Div(Var::Temp, Literal::Int(10)),
+        ));
+        self.cmds.push(Cmd::StartCond(Expr::IsNotEqualLiteral(
+            Var::Temp,
+            Literal::Int(0),
+        )));
+        self.cmds
+            .push(Cmd::CallNoReturn(Function::PutInt(Var::Temp)));
+        self.cmds.push(Cmd::EndIf);
+        self.add_to_var(Var::Digit, b'0' as i64);
+        self.cmds
+            .push(Cmd::Call(Function::IntToChar(Var::Digit), Var::Digit));
+        self.cmds
+            .push(Cmd::CallNoReturn(Function::PutChar(Var::Digit)));
+        self.cmds.push(Cmd::Return(Expr::Literal(Literal::Int(0))));
+    }
+    /// Defines the `ArrayConcat` verse, which moves the elements of its
+    /// second parameter to the end of the first one and returns the first
+    fn define_array_concat(&mut self) {
+        self.cmds.push(Cmd::DeclareFn(Function::ArrayConcat(
+            Var::Tape,
+            Var::Buffer,
+        )));
+        // the verse cannot see the chorus's variables
+        self.cmds.push(Cmd::DeclareVar(Var::Zero));
+        self.cmds.push(Cmd::DeclareVar(Var::Temp));
+        self.cmds.push(Cmd::DeclareVar(Var::Pointer));
+        self.cmds
+            .push(Cmd::Assign(Var::Zero, Expr::Literal(Literal::Int(0))));
+        self.cmds
+            .push(Cmd::Call(Function::ArrayLength(Var::Buffer), Var::Temp));
+        self.cmds.push(Cmd::StartCond(Expr::IsNotEqualLiteral(
+            Var::Temp,
+            Literal::Int(0),
+        )));
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::ArrayAccess(Var::Buffer, Var::Zero),
+        ));
+        self.cmds.push(Cmd::Call(
+            Function::ArrayPop(Var::Buffer, Var::Zero),
+            Var::Buffer,
+        ));
+        self.cmds
+            .push(Cmd::Call(Function::ArrayLength(Var::Tape), Var::Pointer));
+        self.cmds.push(Cmd::Call(
+            Function::ArrayPush(Var::Tape, Var::Pointer, Var::Temp),
+            Var::Tape,
+        ));
+        self.cmds
+            .push(Cmd::Call(Function::ArrayLength(Var::Buffer), Var::Temp));
+        self.cmds.push(Cmd::EndWhile);
+        self.cmds.push(Cmd::Return(Expr::Var(Var::Tape)));
+    }
+    fn emit_instruction(&mut self, instr: &BfInstruction, known: Option<i64>) {
+        match instr {
+            BfInstruction::IncPointer => self.inc_pointer(),
+            BfInstruction::DecPointer => self.dec_pointer(),
+            BfInstruction::IncData => self.inc_data(),
+            BfInstruction::DecData => self.dec_data(),
+            BfInstruction::Output => self.output_byte(known),
+            BfInstruction::Sleep => self.sleep(),
+            BfInstruction::AssertData(n) => self.assert_data(*n),
+            BfInstruction::Input => self.read_byte(),
+            BfInstruction::LoopStart => self.cond_jump(),
+            BfInstruction::LoopEnd => self.cond_jump_end(),
+            BfInstruction::AddData(n) => self.add_data(*n),
+            BfInstruction::ClearData => self.clear_data(),
+        }
+    }
+    /// Defines a `Loop` verse for each number in `outlined`, see
+    /// `outlined_loops`
+    fn define_loops(&mut self, instrs: &[BfInstruction], outlined: &[(Range<usize>, usize)]) {
+        let mut defined = HashSet::new();
+        for (range, n) in outlined {
+            if !defined.insert(*n) {
+                continue;
+            }
+            self.cmds.push(Cmd::DeclareFn(Function::Loop(*n)));
+            // the verse cannot see the chorus's variables
+            self.cmds.push(Cmd::DeclareVar(Var::Zero));
+            self.cmds.push(Cmd::DeclareVar(Var::Temp));
+            self.cmds
+                .push(Cmd::Assign(Var::Zero, Expr::Literal(Literal::Int(0))));
+            for instr in &instrs[range.clone()] {
+                self.emit_instruction(instr, None);
+            }
+            self.cmds.push(Cmd::Return(Expr::Var(Var::Tape)));
+        }
+    }
+    fn declare_chorus(&mut self, label: Option<String>) {
+        let param = self.input_from_arg.then_some(Var::Buffer);
+        self.cmds.push(Cmd::DeclareChorus(label, param));
+    }
+    /// Declares and initializes the variables of the chorus, followed by
+    /// the declarations of `extra`
+    fn init_vars(&mut self, extra: &[Var]) {
+        self.cmds.push(Cmd::DeclareVar(Var::Zero));
+        self.cmds.push(Cmd::DeclareVar(Var::Tape));
+        self.cmds.push(Cmd::DeclareVar(Var::Temp));
+        if !self.input_from_arg {
+            self.cmds.push(Cmd::DeclareVar(Var::Buffer));
+        }
+        match self.pointer_type {
+            PointerType::Index => self.cmds.push(Cmd::DeclareVar(Var::Pointer)),
+            PointerType::Cursor => self.cmds.push(Cmd::DeclareVar(Var::Left)),
+        }
+        for var in extra {
+            self.cmds.push(Cmd::DeclareVar(*var));
+        }
+        self.cmds
+            .push(Cmd::Assign(Var::Zero, Expr::Literal(Literal::Int(0))));
+        self.cmds
+            .push(Cmd::Assign(Var::Tape, Expr::Literal(Literal::EmptyArray)));
+        self.cmds.push(Cmd::Call(
+            Function::ArrayPush(Var::Tape, Var::Zero, Var::Zero),
+            Var::Tape,
+        ));
+        if let Some(input) = self.seed_input.clone().filter(|_| self.input_from_arg) {
+            // the seed is read first, then the chorus's argument
+            self.cmds
+                .push(Cmd::Assign(Var::Temp, Expr::Literal(Literal::Str(input))));
+            self.cmds.push(Cmd::Call(
+                Function::ArrayConcat(Var::Temp, Var::Buffer),
+                Var::Buffer,
+            ));
+        }
+        self.cmds
+            .push(Cmd::Assign(Var::Temp, Expr::Literal(Literal::Int(0))));
+        let buffer = match &self.seed_input {
+            _ if self.input_from_arg => None,
+            Some(input) => Some(Literal::Str(input.clone())),
+            None => Some(Literal::EmptyArray),
+        };
+        if let Some(buffer) = buffer {
+            self.cmds
+                .push(Cmd::Assign(Var::Buffer, Expr::Literal(buffer)));
+        }
+        match self.pointer_type {
+            PointerType::Index => {
+                self.cmds
+                    .push(Cmd::Assign(Var::Pointer, Expr::Literal(Literal::Int(0))));
+                self.add_to_var(Var::Pointer, self.pointer_start as i64);
+            }
+            PointerType::Cursor => {
+                self.cmds
+                    .push(Cmd::Assign(Var::Left, Expr::Literal(Literal::EmptyArray)));
+                for _ in 0..self.pointer_start {
+                    self.inc_pointer();
+                }
+            }
+        }
+    }
+    /// Makes the tape start out with `values` in its first cells instead of
+    /// zero, creating more cells if needed. This changes the commands that
+    /// create the tape, so it has no effect without the preamble. Commands
+    /// generated with `opt_level` above 0 may assume that the tape starts
+    /// out zero, so use `CompilerOptions::tape_init` to compile optimized
+    /// programs for a tape with initial values.
+    pub fn set_tape_initial_values(&mut self, values: &[u8]) -> &mut Self {
+        let Some(start) = self
+            .cmds
+            .iter()
+            .position(|cmd| *cmd == Cmd::Assign(Var::Tape, Expr::Literal(Literal::EmptyArray)))
+        else {
+            return self;
+        };
+        let push = |value| Cmd::Call(Function::ArrayPush(Var::Tape, Var::Zero, value), Var::Tape);
+        // the pushes that create the tape, each with the assignment of its
+        // value to `Temp` if that is not zero
+        let mut end = start + 1;
+        let mut cells = 0usize;
+        loop {
+            match &self.cmds[end..] {
+                [first, ..] if *first == push(Var::Zero) => end += 1,
+                [Cmd::Assign(Var::Temp, Expr::Literal(Literal::Int(_))), second, ..]
+                    if *second == push(Var::Temp) =>
+                {
+                    end += 2
+                }
+                _ => break,
+            }
+            cells += 1;
+        }
+        // cells are pushed at the front, so the last cell comes first
+        let mut pushes = vec![push(Var::Zero); cells.saturating_sub(values.len())];
+        for &value in values.iter().rev() {
+            if value == 0 {
+                pushes.push(push(Var::Zero));
+            } else {
+                pushes.push(Cmd::Assign(Var::Temp, Expr::Literal(Literal::Int(value))));
+                pushes.push(push(Var::Temp));
+            }
+        }
+        self.splice_cmds(start + 1..end, pushes);
+        self.tape_init = values.to_vec();
+        self
+    }
+    /// Adds cells after the first one pushed by `init_vars`
+    fn preallocate_tape(&mut self, cells: usize) {
+        let first_push = self.cmds.iter().position(|cmd| {
+            matches!(
+                cmd,
+                Cmd::Call(
+                    Function::ArrayPush(Var::Tape, Var::Zero, Var::Zero),
+                    Var::Tape
+                )
+            )
+        });
+        if let Some(i) = first_push {
+            let pushes = (0..cells.max(1))
+                .map(|_| {
+                    Cmd::Call(
+                        Function::ArrayPush(Var::Tape, Var::Zero, Var::Zero),
+                        Var::Tape,
+                    )
+                })
+                .collect();
+            self.replace_cmd_at(i, pushes);
+        }
+    }
+    /// The variable holding the index of the current cell in `Tape`
+    fn cell(&self) -> Var {
+        match self.pointer_type {
+            PointerType::Index => Var::Pointer,
+            PointerType::Cursor => Var::Zero,
+        }
+    }
+    fn inc_pointer(&mut self) {
+        if self.pointer_type == PointerType::Cursor {
+            self.cmds.push(Cmd::Assign(
+                Var::Temp,
+                Expr::ArrayAccess(Var::Tape, Var::Zero),
+            ));
+            self.cmds.push(Cmd::Call(
+                Function::ArrayPush(Var::Left, Var::Zero, Var::Temp),
+                Var::Left,
+            ));
+            self.cmds.push(Cmd::Call(
+                Function::ArrayPop(Var::Tape, Var::Zero),
+                Var::Tape,
+            ));
+            // the tape always holds the current cell
+            self.cmds
+                .push(Cmd::Call(Function::ArrayLength(Var::Tape), Var::Temp));
+            self.cmds.push(Cmd::StartCond(Expr::IsEqualLiteral(
+                Var::Temp,
+                Literal::Int(0),
+            )));
+            self.cmds.push(Cmd::Call(
+                Function::ArrayPush(Var::Tape, Var::Zero, Var::Zero),
+                Var::Tape,
+            ));
+            self.cmds.push(Cmd::EndIf);
+            return;
+        }
+        self.cmds
+            .push(Cmd::Assign(Var::Pointer, Expr::Inc(Var::Pointer)));
+        self.extend_tape_to_pointer();
+    }
+    /// Appends a zero cell to the tape if the pointer is just past its end
+    fn extend_tape_to_pointer(&mut self) {
+        self.cmds
+            .push(Cmd::Call(Function::ArrayLength(Var::Tape), Var::Temp));
+        self.cmds
+            .push(Cmd::StartCond(Expr::IsEqualVar(Var::Pointer, Var::Temp)));
+        self.cmds.push(Cmd::Call(
+            Function::ArrayPush(Var::Tape, Var::Temp, Var::Zero),
+            Var::Tape,
+        ));
+        self.cmds.push(Cmd::EndIf);
+    }
+    fn dec_pointer(&mut self) {
+        if self.pointer_type == PointerType::Cursor {
+            if self.check_bounds {
+                self.cmds
+                    .push(Cmd::Call(Function::ArrayLength(Var::Left), Var::Temp));
+                self.cmds.push(Cmd::StartCond(Expr::IsEqualLiteral(
+                    Var::Temp,
+                    Literal::Int(0),
+                )));
+                self.halt_out_of_bounds();
+                self.cmds.push(Cmd::EndIf);
+            }
+            self.cmds.push(Cmd::Assign(
+                Var::Temp,
+                Expr::ArrayAccess(Var::Left, Var::Zero),
+            ));
+            self.cmds.push(Cmd::Call(
+                Function::ArrayPop(Var::Left, Var::Zero),
+                Var::Left,
+            ));
+            self.cmds.push(Cmd::Call(
+                Function::ArrayPush(Var::Tape, Var::Zero, Var::Temp),
+                Var::Tape,
+            ));
+            return;
+        }
+        self.cmds
+            .push(Cmd::Assign(Var::Pointer, Expr::Dec(Var::Pointer)));
+    }
+    /// Moves the pointer to the nearest zero cell in the direction of `step`
+    /// (1 or -1) with a single call of `ArrayFindZero`, for `[>]` and `[<]`
+    fn scan_for_zero(&mut self, step: i64) {
+        // the loop would have read the current cell first
+        self.guard_tape_access();
+        let right = step > 0;
+        let step = if right {
+            Literal::Int(1)
+        } else {
+            Literal::NegativeInt(-1)
+        };
+        self.cmds.push(Cmd::Assign(Var::Temp, Expr::Literal(step)));
+        self.cmds.push(Cmd::Call(
+            Function::ArrayFindZero(Var::Tape, Var::Pointer, Var::Temp),
+            Var::Pointer,
+        ));
+        // the zero found to the right may be the first cell past the end
+        if right {
+            self.extend_tape_to_pointer();
+        }
+        self.guard_tape_access();
+    }
+    /// Adds the current cell times each factor to the cell at its offset and
+    /// clears the current cell, for a multiply loop (see `multiply_loop`).
+    /// The pointer only moves if the loop would have run at all.
+    fn fold_multiply_loop(&mut self, factors: &[(i64, i64)]) {
+        self.cmds.push(Cmd::Assign(
+            Var::Count,
+            Expr::ArrayAccess(Var::Tape, Var::Pointer),
+        ));
+        self.cmds.push(Cmd::StartCond(Expr::IsNotEqualLiteral(
+            Var::Count,
+            Literal::Int(0),
+        )));
+        let mut offset = 0;
+        for &(target, factor) in factors.iter().chain(iter::once(&(0, 0))) {
+            while offset < target {
+                self.inc_pointer();
+                offset += 1;
+            }
+            while offset > target {
+                self.dec_pointer();
+                offset -= 1;
+            }
+            if factor == 0 {
+                break;
+            }
+            let factor = if factor < 0 {
+                Literal::NegativeInt(factor as i8)
+            } else {
+                Literal::Int(factor as u8)
+            };
+            self.cmds.push(Cmd::Assign(
+                Var::Temp,
+                Expr::ArrayAccess(Var::Tape, Var::Pointer),
+            ));
+            self.cmds.push(Cmd::Assign(
+                Var::Temp,
+                Expr::MulAdd(Var::Temp, Var::Count, factor),
+            ));
+            self.cmds.push(Cmd::Call(
+                Function::ArrayReplace(Var::Tape, Var::Pointer, Var::Temp),
+                Var::Tape,
+            ));
+        }
+        self.clear_data();
+        self.cmds.push(Cmd::EndIf);
+    }
+    /// Reads the character of the cell `offset` cells from the pointer into
+    /// `Cached`, if the loop that starts at the pointer will run. The body
+    /// moves to the cell before printing it, so this creates no cells and
+    /// reads no index that running the loop would not.
+    fn cache_output_char(&mut self, offset: i64) {
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::ArrayAccess(Var::Tape, Var::Pointer),
+        ));
+        self.cmds.push(Cmd::StartCond(Expr::IsNotEqualLiteral(
+            Var::Temp,
+            Literal::Int(0),
+        )));
+        for _ in 0..offset {
+            self.inc_pointer();
+        }
+        for _ in offset..0 {
+            self.dec_pointer();
+        }
+        self.cmds.push(Cmd::Assign(
+            Var::Cached,
+            Expr::ArrayAccess(Var::Tape, Var::Pointer),
+        ));
+        self.cmds
+            .push(Cmd::Call(Function::IntToChar(Var::Cached), Var::Cached));
+        for _ in 0..offset {
+            self.dec_pointer();
+        }
+        for _ in offset..0 {
+            self.inc_pointer();
+        }
+        self.cmds.push(Cmd::EndIf);
+    }
+    fn guard_tape_access(&mut self) {
+        // the current cell always exists with a cursor, which is checked
+        // when moving left instead
+        if !self.check_bounds || self.pointer_type == PointerType::Cursor {
+            return;
+        }
+        self.cmds
+            .push(Cmd::Call(Function::ArrayLength(Var::Tape), Var::Temp));
+        self.cmds.push(Cmd::StartCond(Expr::IsGreaterEqualVar(
+            Var::Pointer,
+            Var::Temp,
+        )));
+        self.halt_out_of_bounds();
+        self.cmds.push(Cmd::EndIf);
+        self.cmds
+            .push(Cmd::StartCond(Expr::IsLessVar(Var::Pointer, Var::Zero)));
+        self.halt_out_of_bounds();
+        self.cmds.push(Cmd::EndIf);
+    }
+    fn halt_out_of_bounds(&mut self) {
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::Literal(Literal::Str(
+                "error: tape pointer out of bounds\n".to_owned(),
+            )),
+        ));
+        self.cmds
+            .push(Cmd::CallNoReturn(Function::PutStr(Var::Temp)));
+        self.cmds.push(Cmd::Halt);
+    }
+    fn inc_data(&mut self) {
+        self.guard_tape_access();
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::ArrayAccess(Var::Tape, self.cell()),
+        ));
+        if self.wrap_cells {
+            self.cmds.push(Cmd::StartCond(Expr::IsEqualLiteral(
+                Var::Temp,
+                Literal::Int(u8::MAX),
+            )));
+            self.cmds.push(Cmd::Assign(
+                Var::Temp,
+                Expr::Literal(Literal::NegativeInt(-1)),
+            ));
+            self.cmds.push(Cmd::EndIf);
+        }
+        self.cmds.push(Cmd::Assign(Var::Temp, Expr::Inc(Var::Temp)));
+        self.cmds.push(Cmd::Call(
+            Function::ArrayReplace(Var::Tape, self.cell(), Var::Temp),
+            Var::Tape,
+        ));
+    }
+    fn dec_data(&mut self) {
+        self.guard_tape_access();
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::ArrayAccess(Var::Tape, self.cell()),
+        ));
+        self.cmds.push(Cmd::Assign(Var::Temp, Expr::Dec(Var::Temp)));
+        if self.wrap_cells {
+            self.cmds.push(Cmd::StartCond(Expr::IsEqualLiteral(
+                Var::Temp,
+                Literal::NegativeInt(-1),
+            )));
+            self.cmds
+                .push(Cmd::Assign(Var::Temp, Expr::Literal(Literal::Int(u8::MAX))));
+            self.cmds.push(Cmd::EndIf);
+        }
+        self.cmds.push(Cmd::Call(
+            Function::ArrayReplace(Var::Tape, self.cell(), Var::Temp),
+            Var::Tape,
+        ));
+    }
+    fn add_data(&mut self, amount: i32) {
+        self.guard_tape_access();
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::ArrayAccess(Var::Tape, self.cell()),
+        ));
+        if self.wrap_cells {
+            self.add_to_cell_value(amount as i64);
+        } else {
+            self.add_to_var(Var::Temp, amount as i64);
+        }
+        self.cmds.push(Cmd::Call(
+            Function::ArrayReplace(Var::Tape, self.cell(), Var::Temp),
+            Var::Tape,
+        ));
+    }
+    /// Adds `amount` to the cell value in `Temp`, wrapping around past 255.
+    /// The amount is subtracted as a number from 1 to 256 instead, which
+    /// can only go below zero.
+    fn add_to_cell_value(&mut self, amount: i64) {
+        let amount = amount.rem_euclid(256);
+        if amount == 0 {
+            return;
+        }
+        self.add_to_var(Var::Temp, amount - 256);
+        self.cmds
+            .push(Cmd::StartCond(Expr::IsLessVar(Var::Temp, Var::Zero)));
+        self.add_to_var(Var::Temp, 256);
+        self.cmds.push(Cmd::EndIf);
+    }
+    fn add_to_var(&mut self, var: Var, amount: i64) {
+        // literals are single bytes, so large amounts are added in steps
+        let mut rest = amount;
+        while rest != 0 {
+            let step = rest.clamp(-(u8::MAX as i64), u8::MAX as i64);
+            let literal = Literal::Int(step.unsigned_abs() as u8);
+            self.cmds.push(Cmd::Assign(
+                var,
+                if step > 0 {
+                    Expr::Add(var, literal)
+                } else {
+                    Expr::Sub(var, literal)
+                },
+            ));
+            rest -= step;
+        }
+    }
+    fn assert_data(&mut self, expected: i32) {
+        self.guard_tape_access();
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::ArrayAccess(Var::Tape, self.cell()),
+        ));
+        self.add_to_var(Var::Temp, -(expected as i64));
+        self.cmds.push(Cmd::StartCond(Expr::IsNotEqualLiteral(
+            Var::Temp,
+            Literal::Int(0),
+        )));
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::Literal(Literal::Str(format!(
+                "error: assertion failed: cell == {}, found ",
+                expected
+            ))),
+        ));
+        self.cmds
+            .push(Cmd::CallNoReturn(Function::PutStr(Var::Temp)));
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::ArrayAccess(Var::Tape, self.cell()),
+        ));
+        self.cmds
+            .push(Cmd::CallNoReturn(Function::PutInt(Var::Temp)));
+        self.cmds
+            .push(Cmd::Assign(Var::Temp, Expr::Literal(Literal::Char('\n'))));
+        self.cmds
+            .push(Cmd::CallNoReturn(Function::PutChar(Var::Temp)));
+        self.cmds.push(Cmd::Halt);
+        self.cmds.push(Cmd::EndIf);
+    }
+    fn clear_data(&mut self) {
+        self.guard_tape_access();
         self.cmds
-            .push(Cmd::Call(Function::IntToChar(Var::Temp), Var::Temp));
+            .push(Cmd::Assign(Var::Temp, Expr::Literal(Literal::Int(0))));
+        self.cmds.push(Cmd::Call(
+            Function::ArrayReplace(Var::Tape, self.cell(), Var::Temp),
+            Var::Tape,
+        ));
+    }
+    fn output_byte(&mut self, known: Option<i64>) {
+        self.guard_tape_access();
+        if let Some(value) = known {
+            self.cmds.push(Cmd::Assign(
+                Var::Temp,
+                Expr::Literal(Literal::Char(int_to_char(value))),
+            ));
+        } else {
+            self.cmds.push(Cmd::Assign(
+                Var::Temp,
+                Expr::ArrayAccess(Var::Tape, self.cell()),
+            ));
+            self.cmds
+                .push(Cmd::Call(Function::IntToChar(Var::Temp), Var::Temp));
+        }
         self.cmds
             .push(Cmd::CallNoReturn(Function::PutChar(Var::Temp)));
     }
+    fn sleep(&mut self) {
+        self.guard_tape_access();
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::ArrayAccess(Var::Tape, self.cell()),
+        ));
+        self.cmds
+            .push(Cmd::CallNoReturn(Function::Sleep(Var::Temp)));
+    }
     fn read_byte(&mut self) {
+        self.guard_tape_access();
         self.cmds
             .push(Cmd::Call(Function::ArrayLength(Var::Buffer), Var::Temp));
         self.cmds.push(Cmd::StartCond(Expr::IsEqualLiteral(
             Var::Temp,
             Literal::Int(0),
         )));
-        self.cmds.push(Cmd::Call(Function::ReadLine, Var::Buffer));
+        if self.seed_input.is_some() || self.input_from_arg {
+            // CharToInt maps the pushed 0 to 0
+            self.cmds.push(Cmd::Call(
+                Function::ArrayPush(Var::Buffer, Var::Zero, Var::Zero),
+                Var::Buffer,
+            ));
+        } else {
+            self.cmds.push(Cmd::Call(Function::ReadLine, Var::Buffer));
+        }
         self.cmds.push(Cmd::EndIf);
         self.cmds.push(Cmd::Assign(
             Var::Temp,
@@ -351,21 +3912,559 @@ impl Compiler {
         self.cmds
             .push(Cmd::Call(Function::CharToInt(Var::Temp), Var::Temp));
         self.cmds.push(Cmd::Call(
-            Function::ArrayReplace(Var::Tape, Var::Pointer, Var::Temp),
+            Function::ArrayReplace(Var::Tape, self.cell(), Var::Temp),
             Var::Tape,
         ));
     }
     fn cond_jump(&mut self) {
+        self.guard_tape_access();
         self.cmds.push(Cmd::Assign(
             Var::Temp,
-            Expr::ArrayAccess(Var::Tape, Var::Pointer),
+            Expr::ArrayAccess(Var::Tape, self.cell()),
         ));
         self.cmds.push(Cmd::StartCond(Expr::IsNotEqualLiteral(
             Var::Temp,
             Literal::Int(0),
         )));
+        if self.debug_print_on_loop {
+            self.cmds
+                .push(Cmd::CallNoReturn(Function::DebugPrint(Var::Tape)));
+        }
     }
     fn cond_jump_end(&mut self) {
+        // the loop condition tests Temp again, which the body may have
+        // replaced or left holding a different cell
+        self.guard_tape_access();
+        self.cmds.push(Cmd::Assign(
+            Var::Temp,
+            Expr::ArrayAccess(Var::Tape, self.cell()),
+        ));
         self.cmds.push(Cmd::EndWhile);
     }
+    /// A description of `instr` for `CompilerOptions::explain`
+    fn explain_instruction(&self, instr: &BfInstruction) -> String {
+        let cursor = self.pointer_type == PointerType::Cursor;
+        match instr {
+            BfInstruction::IncPointer if cursor => "> moves to the next cell: the current \
+                cell moves from the front of Tape to the front of Left, and a zero is \
+                added when Tape runs out"
+                .to_owned(),
+            BfInstruction::IncPointer => "> moves to the next cell: Pointer grows by one, \
+                and a zero is pushed onto Tape when it reaches the end, as the tape only \
+                grows when needed"
+                .to_owned(),
+            BfInstruction::DecPointer if cursor => "< moves to the previous cell: the front \
+                of Left moves back to the front of Tape"
+                .to_owned(),
+            BfInstruction::DecPointer => {
+                "< moves to the previous cell: Pointer shrinks by one".to_owned()
+            }
+            BfInstruction::IncData => explain_addition(1),
+            BfInstruction::DecData => explain_addition(-1),
+            BfInstruction::AddData(n) => explain_addition(*n),
+            BfInstruction::ClearData => "[-] sets the current cell to zero, which is \
+                written to the tape directly instead of counting down"
+                .to_owned(),
+            BfInstruction::Output => ". prints the current cell: IntToChar turns the \
+                number into the character PutChar needs, unless the value is known at \
+                compile time"
+                .to_owned(),
+            BfInstruction::Input if self.seed_input.is_some() || self.input_from_arg => {
+                ", reads a character into the current cell: the input is held in Buffer, \
+                 which is used up one character at a time, and CharToInt turns each \
+                 character into a number"
+                    .to_owned()
+            }
+            BfInstruction::Input => ", reads a character into the current cell: ReadLine \
+                reads a whole line into Buffer, which is used up one character at a time, \
+                and CharToInt turns each character into a number"
+                .to_owned(),
+            BfInstruction::LoopStart => "[ starts a loop that runs while the current cell \
+                is not zero: the cell is copied into Temp, as conditions can only test \
+                variables"
+                .to_owned(),
+            BfInstruction::LoopEnd => "] ends the loop: the current cell is copied into \
+                Temp again so that the condition sees its new value"
+                .to_owned(),
+            BfInstruction::AssertData(n) => format!(
+                "assert cell == {} stops the program with an error message unless the \
+                 current cell holds {}",
+                n, n
+            ),
+            BfInstruction::Sleep => "the sleep command passes the current cell to Sleep, \
+                which the runtime provides"
+                .to_owned(),
+        }
+    }
+    fn embed_source(&mut self, program: &str) {
+        self.cmds.push(Cmd::Comment("Brainfuck source:".to_owned()));
+        for line in program.lines() {
+            self.cmds.push(Cmd::Comment(line.to_owned()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A compiler whose generated program is `cmds`
+    fn compiler_with(cmds: Vec<Cmd>) -> Compiler {
+        let opts = CompilerOptions {
+            preamble: false,
+            allow_empty: true,
+            ..CompilerOptions::default()
+        };
+        let mut compiler = Compiler::read("", &opts).unwrap();
+        compiler.cmds = cmds;
+        compiler
+    }
+
+    fn put_char(v: Var) -> Cmd {
+        Cmd::CallNoReturn(Function::PutChar(v))
+    }
+
+    fn assign_char(v: Var, c: char) -> Cmd {
+        Cmd::Assign(v, Expr::Literal(Literal::Char(c)))
+    }
+
+    #[test]
+    fn scratch_vars_that_are_live_one_after_another_are_merged() {
+        let mut compiler = compiler_with(vec![
+            Cmd::DeclareChorus(None, None),
+            Cmd::DeclareVar(Var::Temp),
+            Cmd::DeclareVar(Var::Digit),
+            assign_char(Var::Temp, 'a'),
+            put_char(Var::Temp),
+            assign_char(Var::Digit, 'b'),
+            put_char(Var::Digit),
+        ]);
+        assert_eq!(compiler.reuse_scratch_vars(), 1);
+        assert_eq!(
+            compiler.cmds,
+            vec![
+                Cmd::DeclareChorus(None, None),
+                Cmd::DeclareVar(Var::Temp),
+                assign_char(Var::Temp, 'a'),
+                put_char(Var::Temp),
+                assign_char(Var::Temp, 'b'),
+                put_char(Var::Temp),
+            ]
+        );
+    }
+
+    #[test]
+    fn scratch_vars_that_are_live_at_the_same_time_are_kept() {
+        let cmds = vec![
+            Cmd::DeclareChorus(None, None),
+            Cmd::DeclareVar(Var::Temp),
+            Cmd::DeclareVar(Var::Digit),
+            assign_char(Var::Temp, 'a'),
+            assign_char(Var::Digit, 'b'),
+            put_char(Var::Temp),
+            put_char(Var::Digit),
+        ];
+        let mut compiler = compiler_with(cmds.clone());
+        assert_eq!(compiler.reuse_scratch_vars(), 0);
+        assert_eq!(compiler.cmds, cmds);
+    }
+
+    #[test]
+    fn scratch_vars_used_in_a_loop_are_live_for_the_whole_loop() {
+        // Temp counts the iterations, so Digit must not overwrite it even
+        // though Temp is not used after Digit within one iteration
+        let cmds = vec![
+            Cmd::DeclareChorus(None, None),
+            Cmd::DeclareVar(Var::Temp),
+            Cmd::DeclareVar(Var::Digit),
+            Cmd::Assign(Var::Temp, Expr::Literal(Literal::Int(3))),
+            Cmd::StartCond(Expr::IsNotEqualLiteral(Var::Temp, Literal::Int(0))),
+            Cmd::Assign(Var::Temp, Expr::Dec(Var::Temp)),
+            assign_char(Var::Digit, 'a'),
+            put_char(Var::Digit),
+            Cmd::EndWhile,
+        ];
+        let mut compiler = compiler_with(cmds.clone());
+        assert_eq!(compiler.reuse_scratch_vars(), 0);
+        assert_eq!(compiler.cmds, cmds);
+    }
+
+    #[test]
+    fn leftward_array_search_steps_by_a_negative_literal() {
+        let opts = CompilerOptions {
+            array_search: true,
+            ..CompilerOptions::default()
+        };
+        let program = format!(">+>+[<]{}.", "+".repeat(65));
+        let compiler = Compiler::from_optimized(&program, &opts).unwrap();
+        let step = Cmd::Assign(Var::Temp, Expr::Literal(Literal::NegativeInt(-1)));
+        assert!(compiler.cmds.contains(&step));
+        let mut printed = String::new();
+        compiler.trace_execution(&mut printed).unwrap();
+        assert_eq!(printed, "A");
+    }
+
+    #[test]
+    fn negative_literals_are_rejected_for_index_variables() {
+        let cmd = Cmd::Assign(Var::Pointer, Expr::Literal(Literal::NegativeInt(-1)));
+        let res = Compiler::format_cmd(&cmd, &mut 0, &CompilerOptions::default());
+        assert!(matches!(res, Err(CompilerError::NegativeLiteral)));
+    }
+
+    #[test]
+    fn read_rejects_empty_programs_unless_allowed() {
+        let opts = CompilerOptions::default();
+        assert!(matches!(
+            Compiler::read("no commands here", &opts),
+            Err(CompilerError::EmptyProgram)
+        ));
+        let opts = CompilerOptions {
+            allow_empty: true,
+            ..opts
+        };
+        assert!(Compiler::read("", &opts).is_ok());
+    }
+
+    #[test]
+    fn read_rejects_unbalanced_brackets() {
+        let opts = CompilerOptions::default();
+        assert!(Compiler::read("+[-]", &opts).is_ok());
+        assert!(matches!(
+            Compiler::read("+[-", &opts),
+            Err(CompilerError::UnbalancedBrackets { .. })
+        ));
+        assert!(matches!(
+            Compiler::read("+]", &opts),
+            Err(CompilerError::UnbalancedBrackets { .. })
+        ));
+    }
+
+    #[test]
+    fn split_chorus_keeps_the_output_and_the_line_limit() {
+        let program = "++++++++[>+++++++++<-]>.<+++++[>++++++<-]>-.+++++++..+++.";
+        let opts = CompilerOptions {
+            opt_level: 2,
+            max_chorus_lines: Some(12),
+            ..CompilerOptions::default()
+        };
+        let compiler = Compiler::from_optimized(program, &opts).unwrap();
+        let mut printed = String::new();
+        compiler.trace_execution(&mut printed).unwrap();
+        assert_eq!(printed, "Hello");
+
+        let output = compiler.output(&opts).unwrap();
+        let mut parts: Vec<(&str, usize)> = Vec::new();
+        for line in output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            match parts.last_mut() {
+                Some(part) if !line.starts_with('[') => part.1 += 1,
+                _ => parts.push((line, 1)),
+            }
+        }
+        let split: Vec<_> = parts
+            .iter()
+            .filter(|(header, _)| {
+                header.starts_with("[Chorus") || header.starts_with("[Verse Section")
+            })
+            .collect();
+        assert!(split.len() > 2, "{:?}", parts);
+        assert!(split.iter().all(|(_, lines)| *lines <= 12), "{:?}", parts);
+    }
+
+    #[test]
+    fn from_optimized_at_level_0_matches_read() {
+        let program = "++++++++[>+++++++++<-]>.<+++++[>++++++<-]>-.+++++++..+++.";
+        for max_chorus_lines in [None, Some(12)] {
+            let opts = CompilerOptions {
+                max_chorus_lines,
+                ..CompilerOptions::default()
+            };
+            let read = Compiler::read(program, &opts).unwrap();
+            let optimized = Compiler::from_optimized(program, &opts).unwrap();
+            assert_eq!(
+                read.output(&opts).unwrap(),
+                optimized.output(&opts).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn tab_and_carriage_return_chars_are_rejected() {
+        assert_eq!(Literal::Char('\n').to_string(), "'\\n'");
+        assert_eq!(Literal::Str("a\nb".to_owned()).to_string(), "\"a\\nb\"");
+        for input in ["a\tb", "a\r\n", "\x7f"] {
+            let opts = CompilerOptions {
+                seed_input: Some(input.to_owned()),
+                ..CompilerOptions::default()
+            };
+            let compiler = Compiler::from_optimized(",.", &opts).unwrap();
+            assert!(matches!(
+                compiler.output(&opts),
+                Err(CompilerError::UnprintableLiteral)
+            ));
+        }
+    }
+
+    #[test]
+    fn outlined_output_is_the_same_every_time() {
+        let opts = CompilerOptions {
+            outline_loops: true,
+            ..CompilerOptions::default()
+        };
+        let lp = |n: usize| format!("[->{}<]", "+".repeat(n));
+        let program: String = (0..4)
+            .flat_map(|n| [lp(n + 8), "+>".to_owned(), lp(n + 8), ">+<".to_owned()])
+            .collect();
+        let first = Compiler::from_optimized(&program, &opts).unwrap();
+        assert!(first.cmds.contains(&Cmd::DeclareFn(Function::Loop(4))));
+        let first = first.output(&opts).unwrap();
+        for _ in 0..8 {
+            let again = Compiler::from_optimized(&program, &opts).unwrap();
+            assert_eq!(again.output(&opts).unwrap(), first);
+        }
+    }
+
+    #[test]
+    fn find_pattern_returns_non_overlapping_matches() {
+        let compiler = compiler_with(vec![
+            assign_char(Var::Temp, 'a'),
+            put_char(Var::Temp),
+            put_char(Var::Temp),
+            put_char(Var::Temp),
+            assign_char(Var::Temp, 'b'),
+        ]);
+        let pattern = [CmdPattern::AnyCall, CmdPattern::Wildcard];
+        assert_eq!(compiler.find_pattern(&pattern), [1, 3]);
+        let pattern = [CmdPattern::SpecificCmd(assign_char(Var::Temp, 'b'))];
+        assert_eq!(compiler.find_pattern(&pattern), [4]);
+        assert_eq!(
+            compiler.find_pattern(&[CmdPattern::AnyAssign, CmdPattern::AnyAssign]),
+            []
+        );
+        assert!(compiler.find_pattern(&[]).is_empty());
+    }
+
+    #[test]
+    fn multiply_loops_are_folded_at_o2() {
+        let run = |program: &str, seed: &str| {
+            let opts = CompilerOptions {
+                opt_level: 2,
+                seed_input: Some(seed.to_owned()),
+                ..CompilerOptions::default()
+            };
+            let compiler = Compiler::from_optimized(program, &opts).unwrap();
+            let folded = compiler
+                .cmds
+                .iter()
+                .any(|cmd| matches!(cmd, Cmd::Assign(_, Expr::MulAdd(..))));
+            let mut printed = String::new();
+            compiler.trace_execution(&mut printed).unwrap();
+            (folded, printed)
+        };
+        assert_eq!(run(",[->++>+<<]>.>.", "#"), (true, "F#".to_owned()));
+        assert_eq!(run(",>,<[->-<]>.", "!d"), (true, "C".to_owned()));
+        // the control cell must count down by one
+        assert_eq!(run(",[-->+<]>.", "\n"), (false, "$".to_owned()));
+    }
+
+    #[test]
+    fn wrapping_cells_stay_between_0_and_255() {
+        let run = |program: &str, opts: &CompilerOptions| {
+            let compiler = Compiler::from_optimized(program, opts).unwrap();
+            let mut printed = String::new();
+            compiler.trace_execution(&mut printed).unwrap();
+            printed
+        };
+        let down = format!("{}.", "-".repeat(191));
+        let up = format!("{}.", "+".repeat(256 + 65));
+        for opt_level in [0, 1, 2] {
+            let opts = CompilerOptions {
+                opt_level,
+                wrapping: true,
+                ..CompilerOptions::default()
+            };
+            assert_eq!(run(&down, &opts), "A");
+            assert_eq!(run(&up, &opts), "A");
+        }
+        assert_eq!(run(&down, &CompilerOptions::default()), "$");
+    }
+
+    #[test]
+    fn no_wrap_check_leaves_out_the_guards() {
+        let opts = CompilerOptions {
+            wrapping: true,
+            no_wrap_check: true,
+            ..CompilerOptions::default()
+        };
+        let checked = Compiler::from_optimized(
+            "+-",
+            &CompilerOptions {
+                no_wrap_check: false,
+                ..opts.clone()
+            },
+        )
+        .unwrap();
+        let unchecked = Compiler::from_optimized("+-", &opts).unwrap();
+        let plain = Compiler::from_optimized("+-", &CompilerOptions::default()).unwrap();
+        assert!(checked.cmds.len() > plain.cmds.len());
+        assert_eq!(unchecked.cmds, plain.cmds);
+    }
+
+    #[test]
+    fn long_runs_of_plus_are_added_in_byte_sized_steps() {
+        let program = format!("{}.", "+".repeat(1000));
+        let opts = CompilerOptions {
+            opt_level: 1,
+            ..CompilerOptions::default()
+        };
+        let compiler = Compiler::from_optimized(&program, &opts).unwrap();
+        let steps: Vec<u32> = compiler
+            .cmds
+            .iter()
+            .filter_map(|cmd| match cmd {
+                Cmd::Assign(Var::Temp, Expr::Add(Var::Temp, Literal::Int(n))) => Some(*n as u32),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(steps.iter().sum::<u32>(), 1000);
+        assert!(steps.iter().all(|&n| n <= 255));
+
+        // 1089 is 65 modulo 256
+        let program = format!("{}.", "+".repeat(1089));
+        let opts = CompilerOptions {
+            wrapping: true,
+            ..opts
+        };
+        let compiler = Compiler::from_optimized(&program, &opts).unwrap();
+        let mut printed = String::new();
+        compiler.trace_execution(&mut printed).unwrap();
+        assert_eq!(printed, "A");
+    }
+
+    #[test]
+    fn seed_input_is_read_before_the_chorus_argument() {
+        let opts = CompilerOptions {
+            seed_input: Some("a".to_owned()),
+            input_from_arg: true,
+            ..CompilerOptions::default()
+        };
+        let compiler = Compiler::from_optimized(",.,.", &opts).unwrap();
+        assert!(compiler.cmds.iter().any(|cmd| matches!(
+            cmd,
+            Cmd::Call(Function::ArrayConcat(Var::Temp, Var::Buffer), Var::Buffer)
+        )));
+        // the emulator passes an empty array to the chorus
+        let mut printed = String::new();
+        compiler.trace_execution(&mut printed).unwrap();
+        assert_eq!(printed, "a$");
+    }
+
+    #[test]
+    fn printable_chars_round_trip_through_the_conversion_verses() {
+        let printable: String = (b' '..=b'~').map(char::from).collect();
+        let opts = CompilerOptions {
+            seed_input: Some(printable.clone()),
+            ..CompilerOptions::default()
+        };
+        let program = ",.".repeat(printable.len());
+        let compiler = Compiler::from_optimized(&program, &opts).unwrap();
+        let mut printed = String::new();
+        compiler.trace_execution(&mut printed).unwrap();
+        assert_eq!(printed, printable);
+
+        // bytes without a printable character are shown as `$`
+        let program = format!(".{}.{}.", "+".repeat(31), "+".repeat(96));
+        let compiler = Compiler::from_optimized(&program, &CompilerOptions::default()).unwrap();
+        let mut printed = String::new();
+        compiler.trace_execution(&mut printed).unwrap();
+        assert_eq!(printed, "$$$");
+    }
+
+    #[test]
+    fn unchanged_cells_printed_in_loops_are_read_once() {
+        let run = |program: &str, opt_level| {
+            let opts = CompilerOptions {
+                opt_level,
+                seed_input: Some("#A".to_owned()),
+                ..CompilerOptions::default()
+            };
+            let compiler = Compiler::from_optimized(program, &opts).unwrap();
+            let cached = compiler
+                .cmds
+                .contains(&Cmd::CallNoReturn(Function::PutChar(Var::Cached)));
+            let mut printed = String::new();
+            compiler.trace_execution(&mut printed).unwrap();
+            (cached, printed)
+        };
+        let a = "A".repeat(b'#' as usize);
+        assert_eq!(run(",>,<[>.<-]", 2), (true, a.clone()));
+        assert_eq!(run(",>,<[>.<-]", 0), (false, a.clone()));
+        assert_eq!(run(">,>,[<.>-]", 2), (true, "#".repeat(b'A' as usize)));
+        // `,` reads 0 after the seed, so the loop does not run and must not
+        // read the cell before the tape
+        assert_eq!(run(",,,[<.>-]+.", 2), (true, "$".to_owned()));
+        // the printed cell changes
+        let changed = run(",>,<[>.+<-]", 2);
+        assert!(!changed.0);
+        assert_eq!(changed.1, run(",>,<[>.+<-]", 0).1);
+    }
+
+    #[test]
+    fn integer_overflow_is_an_execution_error() {
+        // the inner loops double the second cell 70 times, past `i64::MAX`
+        let program = format!("{}>+<[>[->++<]>[-<+>]<<-]", "+".repeat(70));
+        let opts = CompilerOptions {
+            opt_level: 2,
+            ..CompilerOptions::default()
+        };
+        let compiler = Compiler::from_optimized(&program, &opts).unwrap();
+        let mut printed = String::new();
+        match compiler.trace_execution(&mut printed) {
+            Err(CompilerError::ExecutionError { message, .. }) => {
+                assert_eq!(message, "integer overflow")
+            }
+            res => panic!("expected an overflow, got {:?}", res.map(|_| printed)),
+        }
+    }
+
+    #[test]
+    fn adjacent_known_prints_are_fused_into_put_str() {
+        let run = |program: &str, opt_level| {
+            let opts = CompilerOptions {
+                opt_level,
+                ..CompilerOptions::default()
+            };
+            let compiler = Compiler::from_optimized(program, &opts).unwrap();
+            let put_str: Vec<_> = compiler
+                .cmds
+                .iter()
+                .filter_map(|cmd| match cmd {
+                    Cmd::Assign(Var::Temp, Expr::Literal(Literal::Str(text))) => Some(text.clone()),
+                    _ => None,
+                })
+                .collect();
+            let mut printed = String::new();
+            // a failing program keeps what it printed before failing
+            let _ = compiler.trace_execution(&mut printed);
+            (put_str, printed)
+        };
+        let mut hello = String::new();
+        let mut cell = 0;
+        for c in "Hello".bytes() {
+            hello.push_str(&"+".repeat(usize::from(c - cell)));
+            hello.push('.');
+            cell = c;
+        }
+        assert_eq!(
+            run(&hello, 2),
+            (vec!["Hello".to_owned()], "Hello".to_owned())
+        );
+        assert_eq!(run(&hello, 0).1, "Hello");
+        // moving left of the first cell fails, which must not hide the "H"
+        let moves_left = format!("{}.<+.", "+".repeat(72));
+        assert_eq!(run(&moves_left, 2), (vec![], "H".to_owned()));
+    }
 }
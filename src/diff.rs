@@ -0,0 +1,136 @@
+use std::fmt::{self, Write};
+
+const CONTEXT: usize = 3;
+
+enum Edit {
+    Keep,
+    Delete,
+    Insert,
+}
+
+/// Shortest edit script between `a` and `b` using Myers' algorithm.
+fn edit_script(a: &[&str], b: &[&str]) -> Vec<Edit> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = n + m;
+    let off = max + 1;
+    let mut v = vec![0isize; 2 * off as usize + 1];
+    // trace[d] holds v[-d - 1..=d + 1] as it was before round d
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    'outer: for d in 0..=max {
+        trace.push(v[(off - d - 1) as usize..=(off + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x =
+                if k == -d || (k != d && v[(off + k - 1) as usize] < v[(off + k + 1) as usize]) {
+                    v[(off + k + 1) as usize]
+                } else {
+                    v[(off + k - 1) as usize] + 1
+                };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(off + k) as usize] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+        }
+    }
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Keep);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert);
+            } else {
+                edits.push(Edit::Delete);
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    edits.reverse();
+    edits
+}
+
+/// Formats the differences between `old` and `new` as unified diff hunks.
+/// Returns an empty string if both texts are identical.
+pub fn unified_diff(old: &str, new: &str) -> Result<String, fmt::Error> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let edits = edit_script(&a, &b);
+    // positions in a and b before each edit
+    let mut pos = Vec::with_capacity(edits.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for edit in &edits {
+        pos.push((i, j));
+        match edit {
+            Edit::Keep => {
+                i += 1;
+                j += 1;
+            }
+            Edit::Delete => i += 1,
+            Edit::Insert => j += 1,
+        }
+    }
+    pos.push((i, j));
+
+    let changes: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| !matches!(e, Edit::Keep))
+        .map(|(idx, _)| idx)
+        .collect();
+    let mut res = String::new();
+    let mut group_start = 0;
+    while group_start < changes.len() {
+        let mut group_end = group_start;
+        while group_end + 1 < changes.len()
+            && changes[group_end + 1] - changes[group_end] <= 2 * CONTEXT + 1
+        {
+            group_end += 1;
+        }
+        let start = changes[group_start].saturating_sub(CONTEXT);
+        let end = (changes[group_end] + CONTEXT + 1).min(edits.len());
+        let (a_start, b_start) = pos[start];
+        let (a_end, b_end) = pos[end];
+        let range = |start: usize, len: usize| {
+            if len == 0 {
+                format!("{},0", start)
+            } else {
+                format!("{},{}", start + 1, len)
+            }
+        };
+        writeln!(
+            res,
+            "@@ -{} +{} @@",
+            range(a_start, a_end - a_start),
+            range(b_start, b_end - b_start)
+        )?;
+        for (edit, &(i, j)) in edits[start..end].iter().zip(&pos[start..end]) {
+            match edit {
+                Edit::Keep => writeln!(res, " {}", a[i])?,
+                Edit::Delete => writeln!(res, "-{}", a[i])?,
+                Edit::Insert => writeln!(res, "+{}", b[j])?,
+            }
+        }
+        group_start = group_end + 1;
+    }
+    Ok(res)
+}
@@ -0,0 +1,76 @@
+use crate::bf::{BfInstruction, BfParser};
+use crate::compiler::CompilerError;
+use std::fmt::Write;
+
+/// Maximum number of instructions shown on one line of a block's label
+const LABEL_WIDTH: usize = 40;
+
+fn block_label(instrs: &str) -> String {
+    if instrs.is_empty() {
+        return "(empty)".to_owned();
+    }
+    let chars: Vec<char> = instrs.chars().collect();
+    chars
+        .chunks(LABEL_WIDTH)
+        .map(|line| line.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("\\n")
+}
+
+/// Renders the control flow of a Brainfuck program as a Graphviz digraph.
+/// Straight-line runs of instructions become boxes `b<n>`, and each loop
+/// becomes a diamond `l<n>` that is entered while the current cell is
+/// nonzero and left once it is zero, with a dashed back-edge from the end of
+/// the loop body.
+pub fn control_flow_graph(program: &[BfInstruction]) -> Result<String, CompilerError> {
+    BfParser::check_balance(program)?;
+    let mut blocks = vec![String::new()];
+    let mut loops = 0;
+    let mut edges = Vec::new();
+    let mut open = Vec::new();
+    for (i, instr) in program.iter().enumerate() {
+        match instr {
+            BfInstruction::LoopStart => {
+                let cond = loops;
+                loops += 1;
+                edges.push(format!("b{} -> l{}", blocks.len() - 1, cond));
+                edges.push(format!("l{} -> b{} [label=\"!= 0\"]", cond, blocks.len()));
+                blocks.push(String::new());
+                open.push(cond);
+            }
+            BfInstruction::LoopEnd => {
+                let cond = open
+                    .pop()
+                    .ok_or(CompilerError::UnbalancedBrackets { position: i })?;
+                edges.push(format!("b{} -> l{} [style=dashed]", blocks.len() - 1, cond));
+                edges.push(format!("l{} -> b{} [label=\"== 0\"]", cond, blocks.len()));
+                blocks.push(String::new());
+            }
+            BfInstruction::AssertData(n) => {
+                let block = blocks.last_mut().expect("there is always a current block");
+                write!(block, "(assert {})", n)?;
+            }
+            _ => {
+                let block = blocks.last_mut().expect("there is always a current block");
+                write!(block, "{}", instr)?;
+            }
+        }
+    }
+    edges.push(format!("b{} -> exit", blocks.len() - 1));
+
+    let mut res = String::new();
+    writeln!(res, "digraph cfg {{")?;
+    writeln!(res, "  node [shape=box, fontname=\"monospace\"];")?;
+    for (i, block) in blocks.iter().enumerate() {
+        writeln!(res, "  b{} [label=\"{}\"];", i, block_label(block))?;
+    }
+    for i in 0..loops {
+        writeln!(res, "  l{} [label=\"[ ]\", shape=diamond];", i)?;
+    }
+    writeln!(res, "  exit [label=\"end\", shape=doublecircle];")?;
+    for edge in edges {
+        writeln!(res, "  {};", edge)?;
+    }
+    writeln!(res, "}}")?;
+    Ok(res)
+}
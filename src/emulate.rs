@@ -0,0 +1,384 @@
+use crate::compiler::{Cmd, Expr, Function, Literal, Var};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+/// Number of commands after which a program is assumed to never finish
+const MAX_STEPS: usize = 10_000_000;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Int(i64),
+    Char(char),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Char(c) => write!(f, "{:?}", c),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Array(_) => write!(f, "an array"),
+        }
+    }
+}
+
+/// How a run of the chorus ended
+pub(crate) enum Outcome {
+    Finished,
+    /// The command at this index would have read a line of input
+    WaitingForInput(usize),
+}
+
+/// Something the program did that has no defined meaning, such as indexing
+/// an array out of its bounds
+pub(crate) struct Fault {
+    /// Index of the command in the chorus that was running, which is the
+    /// call for faults inside verses, or `None` if the chorus never started
+    pub(crate) cmd: Option<usize>,
+    pub(crate) message: String,
+}
+
+/// Where control goes after a command
+enum Flow {
+    Next,
+    Jump(usize),
+    Return(Value),
+}
+
+/// Runs the chorus of `cmds` without input, writing whatever it prints to
+/// `writer`, until it ends or first reads input
+pub(crate) fn run<W: Write>(cmds: &[Cmd], writer: &mut W) -> Result<Outcome, Fault> {
+    let Some(chorus) = cmds
+        .iter()
+        .position(|cmd| matches!(cmd, Cmd::DeclareChorus(..)))
+    else {
+        return Err(Fault {
+            cmd: None,
+            message: "the program has no chorus".to_owned(),
+        });
+    };
+    let mut emulator = Emulator {
+        cmds,
+        ends: matching_ends(cmds),
+        verses: cmds
+            .iter()
+            .enumerate()
+            .filter_map(|(i, cmd)| match cmd {
+                Cmd::DeclareFn(f) => Some((f.name(), i)),
+                _ => None,
+            })
+            .collect(),
+        writer,
+        steps: 0,
+        site: chorus,
+    };
+    let mut vars = HashMap::new();
+    if let Cmd::DeclareChorus(_, Some(param)) = &cmds[chorus] {
+        // the input array, which is empty without input
+        vars.insert(*param, Value::Array(Vec::new()));
+    }
+    match emulator.run_body(chorus + 1, &mut vars, true) {
+        Ok(_) => Ok(Outcome::Finished),
+        Err(Stop::Input) => Ok(Outcome::WaitingForInput(emulator.site)),
+        Err(Stop::Fault(message)) => Err(Fault {
+            cmd: Some(emulator.site),
+            message,
+        }),
+    }
+}
+
+/// The index of the `EndIf` or `EndWhile` closing each `StartCond`, and of
+/// the `StartCond` opening each `EndWhile`
+fn matching_ends(cmds: &[Cmd]) -> HashMap<usize, usize> {
+    let mut ends = HashMap::new();
+    let mut open = Vec::new();
+    for (i, cmd) in cmds.iter().enumerate() {
+        match cmd {
+            Cmd::StartCond(_) => open.push(i),
+            Cmd::EndIf | Cmd::EndWhile => {
+                if let Some(start) = open.pop() {
+                    ends.insert(start, i);
+                    ends.insert(i, start);
+                }
+            }
+            _ => {}
+        }
+    }
+    ends
+}
+
+/// Why a run stopped early
+enum Stop {
+    Input,
+    Fault(String),
+}
+
+impl From<fmt::Error> for Stop {
+    fn from(_: fmt::Error) -> Self {
+        Self::Fault("the trace could not be written".to_owned())
+    }
+}
+
+struct Emulator<'a, W> {
+    cmds: &'a [Cmd],
+    ends: HashMap<usize, usize>,
+    /// The index of the `DeclareFn` of each verse
+    verses: HashMap<String, usize>,
+    writer: &'a mut W,
+    steps: usize,
+    /// See `Fault::cmd`
+    site: usize,
+}
+
+impl<W: Write> Emulator<'_, W> {
+    /// Runs the commands from `start` until a `Return` or the start of the
+    /// next section and returns the returned value
+    fn run_body(
+        &mut self,
+        start: usize,
+        vars: &mut HashMap<Var, Value>,
+        in_chorus: bool,
+    ) -> Result<Value, Stop> {
+        let mut i = start;
+        while let Some(cmd) = self.cmds.get(i) {
+            if matches!(cmd, Cmd::DeclareFn(_) | Cmd::DeclareChorus(..)) {
+                break;
+            }
+            if in_chorus {
+                self.site = i;
+            }
+            self.steps += 1;
+            if self.steps > MAX_STEPS {
+                return Err(Stop::Fault(format!(
+                    "the program did not finish within {} commands",
+                    MAX_STEPS
+                )));
+            }
+            match self.step(i, cmd, vars)? {
+                Flow::Next => i += 1,
+                Flow::Jump(target) => i = target,
+                Flow::Return(value) => return Ok(value),
+            }
+        }
+        Ok(Value::Int(0))
+    }
+    fn step(&mut self, i: usize, cmd: &Cmd, vars: &mut HashMap<Var, Value>) -> Result<Flow, Stop> {
+        match cmd {
+            Cmd::DeclareVar(v) => {
+                vars.insert(*v, Value::Int(0));
+            }
+            Cmd::Assign(v, e) => {
+                let value = eval(e, vars)?;
+                *var_mut(vars, *v)? = value;
+            }
+            Cmd::Call(f, v) => {
+                let value = self.call(f, vars)?;
+                *var_mut(vars, *v)? = value;
+            }
+            Cmd::CallNoReturn(f) => {
+                self.call(f, vars)?;
+            }
+            Cmd::Return(e) => return Ok(Flow::Return(eval(e, vars)?)),
+            Cmd::Halt => return Ok(Flow::Return(Value::Int(0))),
+            Cmd::StartCond(e) => {
+                if !as_bool(&eval(e, vars)?)? {
+                    let end = self.ends.get(&i).ok_or_else(unbalanced)?;
+                    return Ok(Flow::Jump(end + 1));
+                }
+            }
+            Cmd::EndWhile => {
+                let start = self.ends.get(&i).ok_or_else(unbalanced)?;
+                return Ok(Flow::Jump(*start));
+            }
+            Cmd::Trace(position) => writeln!(self.writer, "{}", position)?,
+            Cmd::EndIf | Cmd::Comment(_) | Cmd::DeclareFn(_) | Cmd::DeclareChorus(..) => {}
+        }
+        Ok(Flow::Next)
+    }
+    fn call(&mut self, f: &Function, vars: &HashMap<Var, Value>) -> Result<Value, Stop> {
+        let args: Vec<Value> = f
+            .vars()
+            .iter()
+            .map(|v| var(vars, *v).cloned())
+            .collect::<Result<_, _>>()?;
+        match f {
+            Function::ArrayReplace(_, _, _) => {
+                let mut array = as_array(&args[0])?.to_vec();
+                let index = index(&args[1], array.len())?;
+                array[index] = args[2].clone();
+                Ok(Value::Array(array))
+            }
+            Function::ArrayPush(_, _, _) => {
+                let mut array = as_array(&args[0])?.to_vec();
+                let index = index(&args[1], array.len() + 1)?;
+                array.insert(index, args[2].clone());
+                Ok(Value::Array(array))
+            }
+            Function::ArrayPop(_, _) => {
+                let mut array = as_array(&args[0])?.to_vec();
+                let index = index(&args[1], array.len())?;
+                array.remove(index);
+                Ok(Value::Array(array))
+            }
+            Function::ArrayLength(_) => Ok(Value::Int(as_array(&args[0])?.len() as i64)),
+            Function::PutChar(_) => {
+                self.writer.write_char(as_char(&args[0])?)?;
+                Ok(Value::Int(0))
+            }
+            Function::PutStr(_) => {
+                for c in as_array(&args[0])? {
+                    self.writer.write_char(as_char(c)?)?;
+                }
+                Ok(Value::Int(0))
+            }
+            Function::ReadLine => Err(Stop::Input),
+            // provided by the runtime, and without any effect on the program
+            Function::DebugPrint(_) | Function::Sleep(_) => Ok(Value::Int(0)),
+            Function::ArrayFindZero(_, _, _) => {
+                let array = as_array(&args[0])?;
+                let step = as_int(&args[2])?;
+                let mut i = as_int(&args[1])?;
+                while (0..array.len() as i64).contains(&i) && as_int(&array[i as usize])? != 0 {
+                    i = i.checked_add(step).ok_or_else(overflow)?;
+                }
+                Ok(Value::Int(i.clamp(-1, array.len() as i64)))
+            }
+            Function::CharToInt(_)
+            | Function::IntToChar(_)
+            | Function::PutInt(_)
+            | Function::ArrayConcat(_, _)
+            | Function::Loop(_)
+            | Function::Section(_, _) => {
+                let Some(&decl) = self.verses.get(&f.name()) else {
+                    return Err(Stop::Fault(format!(
+                        "the verse {} is not defined",
+                        f.name()
+                    )));
+                };
+                let Cmd::DeclareFn(params) = &self.cmds[decl] else {
+                    unreachable!("verses are found by their declaration");
+                };
+                // verses cannot see the chorus's variables
+                let mut locals: HashMap<Var, Value> = params.vars().into_iter().zip(args).collect();
+                self.run_body(decl + 1, &mut locals, false)
+            }
+        }
+    }
+}
+
+fn unbalanced() -> Stop {
+    Stop::Fault("the program's conditions are unbalanced".to_owned())
+}
+
+fn var(vars: &HashMap<Var, Value>, v: Var) -> Result<&Value, Stop> {
+    vars.get(&v)
+        .ok_or_else(|| Stop::Fault(format!("{} is used before it is declared", v)))
+}
+
+fn var_mut(vars: &mut HashMap<Var, Value>, v: Var) -> Result<&mut Value, Stop> {
+    vars.get_mut(&v)
+        .ok_or_else(|| Stop::Fault(format!("{} is assigned before it is declared", v)))
+}
+
+fn as_int(value: &Value) -> Result<i64, Stop> {
+    match value {
+        Value::Int(i) => Ok(*i),
+        _ => Err(Stop::Fault(format!("expected an integer, found {}", value))),
+    }
+}
+
+fn as_char(value: &Value) -> Result<char, Stop> {
+    match value {
+        Value::Char(c) => Ok(*c),
+        _ => Err(Stop::Fault(format!(
+            "expected a character, found {}",
+            value
+        ))),
+    }
+}
+
+fn as_bool(value: &Value) -> Result<bool, Stop> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        _ => Err(Stop::Fault(format!(
+            "expected a condition, found {}",
+            value
+        ))),
+    }
+}
+
+fn as_array(value: &Value) -> Result<&[Value], Stop> {
+    match value {
+        Value::Array(array) => Ok(array),
+        _ => Err(Stop::Fault(format!("expected an array, found {}", value))),
+    }
+}
+
+/// `value` as an index into an array with `len` valid indices
+fn index(value: &Value, len: usize) -> Result<usize, Stop> {
+    let i = as_int(value)?;
+    usize::try_from(i)
+        .ok()
+        .filter(|&i| i < len)
+        .ok_or_else(|| Stop::Fault(format!("index {} is out of bounds", i)))
+}
+
+/// The fault of arithmetic whose result does not fit in an `i64`
+fn overflow() -> Stop {
+    Stop::Fault("integer overflow".to_owned())
+}
+
+fn literal(l: &Literal) -> Value {
+    match l {
+        Literal::Char(c) => Value::Char(*c),
+        Literal::Int(i) => Value::Int(i64::from(*i)),
+        Literal::NegativeInt(i) => Value::Int(i64::from(*i)),
+        Literal::Str(s) => Value::Array(s.chars().map(Value::Char).collect()),
+        Literal::EmptyArray => Value::Array(Vec::new()),
+    }
+}
+
+fn eval(e: &Expr, vars: &HashMap<Var, Value>) -> Result<Value, Stop> {
+    let int = |v: &Var| var(vars, *v).and_then(as_int);
+    let lit = |l: &Literal| as_int(&literal(l));
+    let checked = |n: Option<i64>| n.map(Value::Int).ok_or_else(overflow);
+    let value = match e {
+        Expr::Inc(v) => checked(int(v)?.checked_add(1))?,
+        Expr::Dec(v) => checked(int(v)?.checked_sub(1))?,
+        Expr::Add(v, l) => checked(int(v)?.checked_add(lit(l)?))?,
+        Expr::Sub(v, l) => checked(int(v)?.checked_sub(lit(l)?))?,
+        Expr::Div(v, l) | Expr::Mod(v, l) => {
+            let divisor = lit(l)?;
+            if divisor == 0 {
+                return Err(Stop::Fault("division by zero".to_owned()));
+            }
+            match e {
+                Expr::Div(_, _) => checked(int(v)?.checked_div_euclid(divisor))?,
+                _ => checked(int(v)?.checked_rem_euclid(divisor))?,
+            }
+        }
+        Expr::Neg(v) => checked(int(v)?.checked_neg())?,
+        Expr::MulAdd(dest, src, k) => {
+            let dest = int(dest)?;
+            checked(
+                int(src)?
+                    .checked_mul(lit(k)?)
+                    .and_then(|n| n.checked_add(dest)),
+            )?
+        }
+        Expr::ArrayAccess(array, i) => {
+            let array = as_array(var(vars, *array)?)?;
+            array[index(var(vars, *i)?, array.len())?].clone()
+        }
+        Expr::IsEqualLiteral(v, l) => Value::Bool(*var(vars, *v)? == literal(l)),
+        Expr::IsNotEqualLiteral(v, l) => Value::Bool(*var(vars, *v)? != literal(l)),
+        Expr::IsEqualVar(a, b) => Value::Bool(var(vars, *a)? == var(vars, *b)?),
+        Expr::IsLessVar(a, b) => Value::Bool(int(a)? < int(b)?),
+        Expr::IsGreaterEqualVar(a, b) => Value::Bool(int(a)? >= int(b)?),
+        Expr::Literal(l) => literal(l),
+        Expr::Var(v) => var(vars, *v)?.clone(),
+    };
+    Ok(value)
+}
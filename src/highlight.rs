@@ -0,0 +1,97 @@
+const BOLD_CYAN: &str = "\x1b[1;36m";
+const YELLOW: &str = "\x1b[33m";
+const GREEN: &str = "\x1b[32m";
+const MAGENTA: &str = "\x1b[35m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// Fixed phrases of Rickroll statements, longest first so that a phrase is
+/// never shadowed by one of its prefixes
+const KEYWORDS: &[&str] = &[
+    "Your heart's been aching but you're too shy to say it",
+    "(Ooh) Never gonna give, never gonna give (give you",
+    "We know the game and we're gonna play it",
+    "Inside we both know",
+    "Never gonna give",
+    "Never gonna let",
+    "Never gonna run",
+    "Never gonna say",
+    "(Ooh give you",
+    "and desert",
+    "down",
+];
+
+const VARIABLES: &[&str] = &["Zero", "Pointer", "Tape", "Temp", "Buffer", "Digit"];
+
+/// Adds ANSI colors to lines of Rickroll code: section headers in bold
+/// cyan, statement keywords in magenta, the compiler's variables in yellow,
+/// literals in green and comments dimmed.
+pub struct Highlighter;
+
+impl Highlighter {
+    pub fn highlight_line(&self, line: &str) -> String {
+        let code = line.trim_start();
+        let indent = &line[..line.len() - code.len()];
+        if code.starts_with('[') {
+            return format!("{}{}{}{}", indent, BOLD_CYAN, code, RESET);
+        }
+        if code.starts_with('#') {
+            return format!("{}{}{}{}", indent, DIM, code, RESET);
+        }
+        let mut res = String::from(indent);
+        let mut rest = code;
+        let mut word_start = true;
+        while let Some(c) = rest.chars().next() {
+            let keyword = KEYWORDS.iter().find(|k| word_start && rest.starts_with(*k));
+            let len = if let Some(keyword) = keyword {
+                paint(&mut res, MAGENTA, keyword);
+                keyword.len()
+            } else if c == '\'' || c == '"' {
+                let len = quoted_len(rest, c);
+                paint(&mut res, GREEN, &rest[..len]);
+                len
+            } else if c.is_alphanumeric() {
+                let len = rest
+                    .find(|c: char| !c.is_alphanumeric())
+                    .unwrap_or(rest.len());
+                let word = &rest[..len];
+                if VARIABLES.contains(&word) {
+                    paint(&mut res, YELLOW, word);
+                } else if word == "ARRAY" || word.chars().all(|c| c.is_ascii_digit()) {
+                    paint(&mut res, GREEN, word);
+                } else {
+                    res.push_str(word);
+                }
+                len
+            } else {
+                res.push(c);
+                c.len_utf8()
+            };
+            word_start = !rest[..len].ends_with(char::is_alphanumeric);
+            rest = &rest[len..];
+        }
+        res
+    }
+}
+
+fn paint(res: &mut String, color: &str, text: &str) {
+    res.push_str(color);
+    res.push_str(text);
+    res.push_str(RESET);
+}
+
+/// Length of the quoted literal at the start of `text`, including both
+/// quotes and any backslash escapes. Runs to the end if it is unterminated.
+fn quoted_len(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return i + c.len_utf8();
+        }
+    }
+    text.len()
+}
@@ -0,0 +1,10 @@
+pub mod bf;
+pub mod compat;
+pub mod compiler;
+mod diff;
+pub mod dot;
+mod emulate;
+pub mod highlight;
+pub mod llvm;
+pub mod opt;
+mod pretty;
@@ -0,0 +1,185 @@
+use crate::bf::{BfInstruction, BfParser};
+use crate::compiler::CompilerError;
+use std::fmt::Write;
+
+/// Number of cells on the tape of the generated program
+const TAPE_SIZE: usize = 30000;
+
+/// Translates Brainfuck into a textual LLVM IR module defining `main`. The
+/// tape is an `alloca [30000 x i8]` of wrapping byte cells and the pointer an
+/// `i32`, which is not checked against the bounds of the tape. Input and
+/// output go through the C library's `getchar` and `putchar`, with `,`
+/// reading 0 at the end of input. `Sleep` calls the C library's `sleep`,
+/// so the cell counts seconds.
+#[derive(Default)]
+pub struct LlvmEmitter {
+    res: String,
+    values: usize,
+    labels: usize,
+}
+
+impl LlvmEmitter {
+    pub fn new() -> LlvmEmitter {
+        Self::default()
+    }
+    pub fn emit(mut self, program: &[BfInstruction]) -> Result<String, CompilerError> {
+        BfParser::check_balance(program)?;
+        writeln!(self.res, "; generated by brickroll")?;
+        writeln!(self.res, "declare i32 @putchar(i32)")?;
+        writeln!(self.res, "declare i32 @getchar()")?;
+        writeln!(self.res, "declare void @abort()")?;
+        if program.contains(&BfInstruction::Sleep) {
+            writeln!(self.res, "declare i32 @sleep(i32)")?;
+        }
+        writeln!(
+            self.res,
+            "declare void @llvm.memset.p0.i64(ptr, i8, i64, i1 immarg)"
+        )?;
+        writeln!(self.res)?;
+        writeln!(self.res, "define i32 @main() {{")?;
+        writeln!(self.res, "entry:")?;
+        writeln!(self.res, "  %tape = alloca [{} x i8]", TAPE_SIZE)?;
+        writeln!(
+            self.res,
+            "  call void @llvm.memset.p0.i64(ptr %tape, i8 0, i64 {}, i1 false)",
+            TAPE_SIZE
+        )?;
+        writeln!(self.res, "  %ptr = alloca i32")?;
+        writeln!(self.res, "  store i32 0, ptr %ptr")?;
+        let mut open = Vec::new();
+        for instr in program {
+            match instr {
+                BfInstruction::IncPointer => self.move_pointer(1)?,
+                BfInstruction::DecPointer => self.move_pointer(-1)?,
+                BfInstruction::IncData => self.add(1)?,
+                BfInstruction::DecData => self.add(-1)?,
+                BfInstruction::AddData(n) => self.add(*n)?,
+                BfInstruction::ClearData => {
+                    let cell = self.cell()?;
+                    writeln!(self.res, "  store i8 0, ptr {}", cell)?;
+                }
+                BfInstruction::Output => {
+                    let cell = self.cell()?;
+                    let byte = self.value();
+                    let c = self.value();
+                    writeln!(self.res, "  {} = load i8, ptr {}", byte, cell)?;
+                    writeln!(self.res, "  {} = zext i8 {} to i32", c, byte)?;
+                    writeln!(self.res, "  call i32 @putchar(i32 {})", c)?;
+                }
+                BfInstruction::Sleep => {
+                    let cell = self.cell()?;
+                    let byte = self.value();
+                    let seconds = self.value();
+                    writeln!(self.res, "  {} = load i8, ptr {}", byte, cell)?;
+                    writeln!(self.res, "  {} = zext i8 {} to i32", seconds, byte)?;
+                    writeln!(self.res, "  call i32 @sleep(i32 {})", seconds)?;
+                }
+                BfInstruction::Input => {
+                    let c = self.value();
+                    let eof = self.value();
+                    let input = self.value();
+                    let byte = self.value();
+                    writeln!(self.res, "  {} = call i32 @getchar()", c)?;
+                    writeln!(self.res, "  {} = icmp eq i32 {}, -1", eof, c)?;
+                    writeln!(
+                        self.res,
+                        "  {} = select i1 {}, i32 0, i32 {}",
+                        input, eof, c
+                    )?;
+                    writeln!(self.res, "  {} = trunc i32 {} to i8", byte, input)?;
+                    let cell = self.cell()?;
+                    writeln!(self.res, "  store i8 {}, ptr {}", byte, cell)?;
+                }
+                BfInstruction::LoopStart => {
+                    let n = self.labels;
+                    self.labels += 1;
+                    open.push(n);
+                    writeln!(self.res, "  br label %cond{}", n)?;
+                    writeln!(self.res, "cond{}:", n)?;
+                    let nonzero = self.compare("ne", 0)?;
+                    writeln!(
+                        self.res,
+                        "  br i1 {}, label %body{}, label %end{}",
+                        nonzero, n, n
+                    )?;
+                    writeln!(self.res, "body{}:", n)?;
+                }
+                BfInstruction::LoopEnd => {
+                    let n = open.pop().expect("brackets are balanced");
+                    writeln!(self.res, "  br label %cond{}", n)?;
+                    writeln!(self.res, "end{}:", n)?;
+                }
+                BfInstruction::AssertData(n) => {
+                    let label = self.labels;
+                    self.labels += 1;
+                    let ok = self.compare("eq", *n)?;
+                    writeln!(
+                        self.res,
+                        "  br i1 {}, label %ok{}, label %fail{}",
+                        ok, label, label
+                    )?;
+                    writeln!(self.res, "fail{}:", label)?;
+                    writeln!(self.res, "  call void @abort()")?;
+                    writeln!(self.res, "  unreachable")?;
+                    writeln!(self.res, "ok{}:", label)?;
+                }
+            }
+        }
+        writeln!(self.res, "  ret i32 0")?;
+        writeln!(self.res, "}}")?;
+        Ok(self.res)
+    }
+    /// A fresh SSA value name
+    fn value(&mut self) -> String {
+        self.values += 1;
+        format!("%v{}", self.values)
+    }
+    /// Emits the address of the current cell and returns its name
+    fn cell(&mut self) -> Result<String, CompilerError> {
+        let pointer = self.value();
+        let cell = self.value();
+        writeln!(self.res, "  {} = load i32, ptr %ptr", pointer)?;
+        writeln!(
+            self.res,
+            "  {} = getelementptr inbounds [{} x i8], ptr %tape, i32 0, i32 {}",
+            cell, TAPE_SIZE, pointer
+        )?;
+        Ok(cell)
+    }
+    fn move_pointer(&mut self, step: i32) -> Result<(), CompilerError> {
+        let pointer = self.value();
+        let moved = self.value();
+        writeln!(self.res, "  {} = load i32, ptr %ptr", pointer)?;
+        writeln!(self.res, "  {} = add i32 {}, {}", moved, pointer, step)?;
+        writeln!(self.res, "  store i32 {}, ptr %ptr", moved)?;
+        Ok(())
+    }
+    fn add(&mut self, amount: i32) -> Result<(), CompilerError> {
+        // cells wrap around, so only the amount modulo 256 matters
+        let amount = amount.rem_euclid(256) as u8 as i8;
+        if amount == 0 {
+            return Ok(());
+        }
+        let cell = self.cell()?;
+        let old = self.value();
+        let new = self.value();
+        writeln!(self.res, "  {} = load i8, ptr {}", old, cell)?;
+        writeln!(self.res, "  {} = add i8 {}, {}", new, old, amount)?;
+        writeln!(self.res, "  store i8 {}, ptr {}", new, cell)?;
+        Ok(())
+    }
+    /// Emits a comparison of the current cell with `n` (as a byte) and
+    /// returns the name of the result
+    fn compare(&mut self, predicate: &str, n: i32) -> Result<String, CompilerError> {
+        let cell = self.cell()?;
+        let byte = self.value();
+        let res = self.value();
+        writeln!(self.res, "  {} = load i8, ptr {}", byte, cell)?;
+        writeln!(
+            self.res,
+            "  {} = icmp {} i8 {}, {}",
+            res, predicate, byte, n as i8
+        )?;
+        Ok(res)
+    }
+}
@@ -1,9 +1,160 @@
-mod compiler;
-
-use crate::compiler::Compiler;
-use clap::Parser;
-use std::fs;
+use brickroll::bf::BfDialect;
+use brickroll::compat::{self, Interpreter};
+use brickroll::compiler::{
+    self, Compiler, CompilerError, CompilerOptions, Layout, PointerType, RickrollVersion,
+    TapePreallocate,
+};
+use brickroll::dot;
+use brickroll::llvm::LlvmEmitter;
+use brickroll::opt::PassKind;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Optional compiler features reported by `--version-json`
+const CAPABILITIES: &[&str] = &[
+    "trace",
+    "no-preamble",
+    "chorus-name",
+    "embed-source",
+    "explain",
+    "optimize",
+    "range",
+    "target",
+    "emit-bf",
+    "check-bounds",
+    "threads",
+    "glob",
+    "pretty-ir",
+    "layout",
+    "debug-print-on-loop",
+    "emit-dot",
+    "tape-preallocate",
+    "seed-input",
+    "assertions",
+    "pointer-start",
+    "line-numbers",
+    "color",
+    "list-passes",
+    "passes",
+    "incremental",
+    "llvm",
+    "base64",
+    "split-on-bang",
+    "output-encoding",
+    "report-optimization",
+    "warn",
+    "from-markdown",
+    "chunk-size",
+    "sleep",
+    "allow-empty",
+    "bf-dialect",
+    "compat-check",
+    "stats-header",
+    "input-from-arg",
+    "library-shim",
+    "outline-loops",
+    "abort-on-warning",
+    "print-stats",
+    "comment-char",
+    "tape-init",
+    "pointer-type",
+    "array-search",
+    "max-chorus-lines",
+    "wrapping",
+    "no-wrap-check",
+    #[cfg(feature = "compression")]
+    "gzip",
+    #[cfg(feature = "hash")]
+    "print-hash",
+];
+
+/// Language to compile to
+#[derive(Clone, Copy)]
+enum Target {
+    Rickroll(RickrollVersion),
+    /// Experimental: textual LLVM IR
+    Llvm,
+}
+
+/// Initial values of the tape's first cells, as given to --tape-init. A
+/// plain `Vec<u8>` would make clap expect one byte per value.
+#[derive(Clone)]
+struct TapeInit(Vec<u8>);
+
+/// When to highlight Rickroll output
+#[derive(Clone, Copy)]
+enum ColorChoice {
+    Always,
+    /// Only when the output file is a terminal
+    Auto,
+    Never,
+}
+
+/// Optional additions to the Brainfuck language
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Extension {
+    /// A command that waits for as long as the current cell says
+    Sleep,
+}
+
+/// Character encoding of the output files
+#[derive(Clone, Copy)]
+enum OutputEncoding {
+    Utf8,
+    Latin1,
+}
+
+/// How to read each input file and what to write for it
+#[derive(Clone, Copy)]
+struct FileOptions {
+    emit: Emit,
+    encoding: OutputEncoding,
+    /// The files are encoded as base64
+    base64: bool,
+    /// Only compile the `brainfuck` code blocks of Markdown files
+    from_markdown: bool,
+    /// Print how much optimization shrank each file to stderr
+    report_optimization: bool,
+    /// Print warnings about likely mistakes to stderr
+    warn: bool,
+    /// Fail instead of writing the output if there are any warnings
+    abort_on_warning: bool,
+    /// Reject programs that need features this interpreter lacks
+    compat_check: Option<&'static Interpreter>,
+    /// Print static measurements of each file to stderr
+    print_stats: bool,
+    /// Print the hash of each file's commands to stderr
+    #[cfg(feature = "hash")]
+    print_hash: bool,
+}
+
+/// What to write for each input file
+#[derive(Clone, Copy)]
+enum Emit {
+    Rickroll(ColorChoice),
+    NumberedRickroll,
+    /// Rickroll after a header of statistics comments
+    StatsRickroll,
+    /// Only the verses of the Rickroll output
+    LibraryShim,
+    Bf,
+    PrettyIr,
+    Dot,
+    Llvm,
+    /// Rickroll split into files of at most this many lines
+    Chunked(usize),
+    #[cfg(feature = "compression")]
+    Gzip,
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -16,29 +167,1003 @@ struct Args {
     #[arg(long)]
     trace: bool,
 
-    /// Name of output Rickroll file
-    #[arg(short)]
-    output: String,
+    /// Only emit the chorus body, without the CharToInt/IntToChar verses,
+    /// the [Chorus] header or variable initialization (the surrounding
+    /// Rickroll program must provide them)
+    #[arg(long)]
+    no_preamble: bool,
+
+    /// Only emit the verses, without the chorus, for including in other
+    /// Rickroll programs (the opposite of --no-preamble)
+    #[arg(
+        long,
+        conflicts_with_all = ["no_preamble", "emit_bf", "pretty_ir", "emit_dot", "line_numbers", "chunk_size", "stats_header"]
+    )]
+    library_shim: bool,
+
+    /// Label the main section as [Chorus <name>] instead of [Chorus]
+    #[arg(long, value_name = "name")]
+    chorus_name: Option<String>,
+
+    /// Append the Brainfuck source to the output as Rickroll comments
+    #[arg(long)]
+    embed_source: bool,
+
+    /// Precede the Rickroll of each Brainfuck command with a comment that
+    /// explains what the command does and how it is compiled
+    #[arg(long, conflicts_with_all = ["emit_bf", "pretty_ir", "emit_dot"])]
+    explain: bool,
+
+    /// Optimization level (0 disables all optimization passes)
+    #[arg(short = 'O', default_value_t = 0)]
+    opt_level: u8,
+
+    /// Run these optimization passes, in this order, instead of the ones
+    /// selected by -O (see --list-passes)
+    #[arg(long, value_name = "PASS,...", value_delimiter = ',', value_parser = parse_pass)]
+    passes: Option<Vec<PassKind>>,
+
+    /// Only compile Brainfuck commands START (inclusive) to END (exclusive),
+    /// counting commands only
+    #[arg(long, value_name = "START:END", value_parser = parse_range)]
+    range: Option<(usize, usize)>,
+
+    /// Rickroll language version to generate (rickroll@1 or rickroll@2), or
+    /// llvm for experimental LLVM IR output
+    #[arg(long, default_value = "rickroll@1", value_parser = parse_target)]
+    target: Target,
+
+    /// Place the CharToInt/IntToChar verses before the chorus (verses-first)
+    /// or after it (chorus-first)
+    #[arg(long, default_value = "verses-first", value_parser = parse_layout)]
+    layout: Layout,
+
+    /// Keep the current cell's index in Pointer (index) or move cells
+    /// between Tape and a Left array as the pointer moves (cursor), which
+    /// avoids index arithmetic
+    #[arg(long, value_name = "TYPE", default_value = "index", value_parser = parse_pointer_type)]
+    pointer_type: PointerType,
+
+    /// Compile [>] and [<] into a single call of ArrayFindZero, which finds
+    /// the nearest zero cell (the Rickroll runtime must provide an
+    /// ArrayFindZero function)
+    #[arg(long)]
+    array_search: bool,
+
+    /// Move the end of the chorus into Section verses that the chorus calls
+    /// one after another, so that no part of it is longer than N lines (a
+    /// top-level loop that is longer than N lines gets a longer verse of its
+    /// own)
+    #[arg(long, value_name = "N", value_parser = parse_chunk_size)]
+    max_chorus_lines: Option<usize>,
+
+    /// Write the parsed (and optimized) program back out as Brainfuck
+    /// instead of compiling it
+    #[arg(long)]
+    emit_bf: bool,
+
+    /// Write the generated program as Rust-like pseudocode instead of
+    /// Rickroll, for inspecting the compiler's output
+    #[arg(long, conflicts_with = "emit_bf")]
+    pretty_ir: bool,
+
+    /// Write the control flow graph of the parsed (and optimized) program
+    /// in Graphviz DOT format instead of compiling it
+    #[arg(long, conflicts_with_all = ["emit_bf", "pretty_ir"])]
+    emit_dot: bool,
+
+    /// Prefix each line of the Rickroll output with its line number
+    #[arg(long, conflicts_with_all = ["emit_bf", "pretty_ir", "emit_dot"])]
+    line_numbers: bool,
+
+    /// Highlight the Rickroll output with ANSI colors (always, auto or never;
+    /// auto only colors output written to a terminal)
+    #[arg(long, value_name = "WHEN", default_value = "auto", value_parser = parse_color)]
+    color: ColorChoice,
+
+    /// Split the Rickroll output into files of at most N lines, named like
+    /// out.0001.rickroll for -o out.rickroll (a verse or top-level loop that
+    /// is longer than N lines gets a longer file of its own)
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = parse_chunk_size,
+        conflicts_with_all = ["emit_bf", "pretty_ir", "emit_dot", "line_numbers"]
+    )]
+    chunk_size: Option<usize>,
+
+    /// Encoding of the output files (utf8 or latin1); compressed output is
+    /// always UTF-8
+    #[arg(long, value_name = "ENCODING", default_value = "utf8", value_parser = parse_encoding)]
+    output_encoding: OutputEncoding,
+
+    /// Start the output with comments describing the build: brickroll's
+    /// version, the time, the source's hash, the number of Brainfuck
+    /// commands and Rickroll lines, the optimization level and the deepest
+    /// loop nesting
+    #[arg(
+        long,
+        conflicts_with_all = ["emit_bf", "pretty_ir", "emit_dot", "line_numbers", "chunk_size"]
+    )]
+    stats_header: bool,
+
+    /// Compress the Rickroll output with gzip, adding .gz to the output
+    /// file names
+    #[cfg(feature = "compression")]
+    #[arg(
+        long,
+        conflicts_with_all = ["emit_bf", "pretty_ir", "emit_dot", "line_numbers", "chunk_size", "stats_header", "library_shim"]
+    )]
+    gzip: bool,
+
+    /// Warn about likely mistakes, such as programs that never print
+    /// anything or `[]` loops (warnings do not change the exit code)
+    #[arg(long)]
+    warn: bool,
+
+    /// Like --warn, but fail without writing the output if there are any
+    /// warnings
+    #[arg(long)]
+    abort_on_warning: bool,
+
+    /// Fail for programs that need features the named Rickroll interpreter
+    /// does not support (rickroll for the reference interpreter, or minimal
+    /// for one without characters or I/O)
+    #[arg(long, value_name = "INTERPRETER", value_parser = parse_interpreter)]
+    compat_check: Option<&'static Interpreter>,
+
+    /// Print how many lines optimization saved, and what each optimization
+    /// did, to stderr
+    #[arg(long)]
+    report_optimization: bool,
+
+    /// Print measurements of each compiled program to stderr: its tape
+    /// reads and writes, loop iterations if they are known, verses and
+    /// estimated time complexity
+    #[arg(long)]
+    print_stats: bool,
+
+    /// Print the SHA-256 hash of each input's Brainfuck commands to stderr
+    #[cfg(feature = "hash")]
+    #[arg(long)]
+    print_hash: bool,
+
+    /// Stop with an error message when the program accesses the tape
+    /// outside of its bounds
+    #[arg(long)]
+    check_bounds: bool,
+
+    /// Make cells wrap around like bytes (- on 0 gives 255 and + on 255
+    /// gives 0), checking every change of a cell
+    #[arg(long)]
+    wrapping: bool,
+
+    /// With --wrapping, leave out the checks and trust the program to keep
+    /// every cell between 0 and 255; cells of a program that does not are
+    /// silently wrong (for trusted programs only)
+    #[arg(long, requires = "wrapping", conflicts_with = "check_bounds")]
+    no_wrap_check: bool,
+
+    /// Call DebugPrint with the tape at the start of every loop iteration
+    /// (the Rickroll runtime must provide a DebugPrint function)
+    #[arg(long)]
+    debug_print_on_loop: bool,
+
+    /// Create this many tape cells up front, or as many as the program is
+    /// known to use with "auto"
+    #[arg(long, value_name = "N|auto", value_parser = parse_tape_preallocate)]
+    tape_preallocate: Option<TapePreallocate>,
+
+    /// Start the program with these bytes, written as hexadecimal such as
+    /// 0a2f, in the first cells of the tape instead of zero. Optimizations
+    /// that rely on a zeroed tape are skipped.
+    #[arg(long, value_name = "HEX", value_parser = parse_tape_init, conflicts_with = "no_preamble")]
+    tape_init: Option<TapeInit>,
+
+    /// Compile TEXT into the program as its input instead of reading stdin
+    /// (`,` reads 0 after the end of TEXT)
+    #[arg(long, value_name = "TEXT")]
+    seed_input: Option<String>,
+
+    /// Treat everything after the first `!` in an input file as the
+    /// program's input, like --seed-input
+    #[arg(long, conflicts_with = "seed_input")]
+    split_on_bang: bool,
+
+    /// Make the chorus take the program's input as an array of characters
+    /// in its Buffer parameter instead of reading stdin (`,` reads 0 after
+    /// the end of the array). Input from --seed-input or --split-on-bang is
+    /// read first.
+    #[arg(long)]
+    input_from_arg: bool,
+
+    /// Check `; assert cell == N` comments at runtime, halting with an error
+    /// if the current cell holds a different value
+    #[arg(long)]
+    assertions: bool,
+
+    /// Enable these language extensions (sleep: a `*` command that calls a
+    /// runtime-provided Sleep function with the current cell)
+    #[arg(long, value_name = "NAME,...", value_delimiter = ',', value_parser = parse_extension)]
+    extension: Vec<Extension>,
+
+    /// Character of the sleep extension's command [default: *]
+    #[arg(long, value_name = "C", value_parser = parse_sleep_char, requires = "extension")]
+    sleep_char: Option<char>,
+
+    /// Ignore everything from this character to the end of the line, even
+    /// Brainfuck commands (`; assert` comments still work with `;`)
+    #[arg(long, value_name = "C", value_parser = parse_comment_char)]
+    comment_char: Option<char>,
+
+    /// Language of the input files (standard, ook for Ook!, brainfuck2 for
+    /// commands spelled as words, or whitespace for Ook! spelled with spaces,
+    /// tabs and newlines)
+    #[arg(long, value_name = "DIALECT", default_value = "standard", value_parser = parse_dialect)]
+    bf_dialect: BfDialect,
+
+    /// Experimental: compile loops that occur several times into a verse
+    /// each, which every occurrence calls
+    #[arg(long)]
+    outline_loops: bool,
+
+    /// Compile input files without any Brainfuck commands instead of
+    /// reporting an error
+    #[arg(long)]
+    allow_empty: bool,
+
+    /// Start the pointer at tape index N, with the cells to its left
+    /// available as scratch space
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pointer_start: usize,
+
+    /// Print the version, git commit and supported features as JSON and exit
+    #[arg(long)]
+    version_json: bool,
+
+    /// Print the available optimization passes and exit
+    #[arg(long)]
+    list_passes: bool,
+
+    /// Directory to write the compiled files to, named after their inputs
+    #[arg(long, value_name = "DIR")]
+    out_dir: Option<PathBuf>,
+
+    /// Also compile every file matching PATTERN (requires --out-dir)
+    #[arg(long, value_name = "PATTERN", requires = "out_dir")]
+    glob: Option<String>,
+
+    /// Skip inputs whose output file is newer than the input
+    #[arg(long)]
+    incremental: bool,
+
+    /// Number of files to compile in parallel [default: number of CPUs]
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Name of output Rickroll file [default: the input file with its
+    /// extension replaced by .rick, or by .ll with --target llvm]
+    #[arg(short, conflicts_with = "out_dir")]
+    output: Option<PathBuf>,
+
+    /// Read the input files as base64-encoded Brainfuck
+    #[arg(long)]
+    base64: bool,
+
+    /// Read the input files as Markdown, compiling the contents of all
+    /// ```brainfuck code blocks in order
+    #[arg(long)]
+    from_markdown: bool,
+
+    /// Names of input Brainfuck files
+    #[arg(required_unless_present_any = ["version_json", "list_passes", "glob"])]
+    files: Vec<String>,
+}
+
+fn parse_range(s: &str) -> Result<(usize, usize), String> {
+    let (start, end) = s
+        .split_once(':')
+        .ok_or_else(|| "expected START:END".to_owned())?;
+    let start = start.parse::<usize>().map_err(|e| e.to_string())?;
+    let end = end.parse::<usize>().map_err(|e| e.to_string())?;
+    if start > end {
+        return Err("START must not be greater than END".to_owned());
+    }
+    Ok((start, end))
+}
+
+fn parse_pass(s: &str) -> Result<PassKind, String> {
+    PassKind::from_name(s).ok_or_else(|| match closest_pass(s) {
+        Some(pass) => format!("unknown pass \"{}\", did you mean \"{}\"?", s, pass.name()),
+        None => format!("unknown pass \"{}\" (see --list-passes)", s),
+    })
+}
+
+/// The pass whose name is within a few edits of `name`, if any
+fn closest_pass(name: &str) -> Option<PassKind> {
+    PassKind::ALL
+        .iter()
+        .map(|pass| (edit_distance(name, pass.name()), *pass))
+        .filter(|(distance, _)| *distance <= 3)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, pass)| pass)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(ca != *cb);
+            row.push(substitute.min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+fn parse_target(s: &str) -> Result<Target, String> {
+    match s {
+        "rickroll@1" => Ok(Target::Rickroll(RickrollVersion::V1)),
+        "rickroll@2" => Ok(Target::Rickroll(RickrollVersion::V2)),
+        "llvm" => Ok(Target::Llvm),
+        _ => Err("expected rickroll@1, rickroll@2 or llvm".to_owned()),
+    }
+}
+
+fn parse_interpreter(s: &str) -> Result<&'static Interpreter, String> {
+    Interpreter::find(s).ok_or_else(|| {
+        let names: Vec<&str> = compat::INTERPRETERS.iter().map(|i| i.name).collect();
+        format!("expected one of {}", names.join(", "))
+    })
+}
+
+fn parse_layout(s: &str) -> Result<Layout, String> {
+    match s {
+        "verses-first" => Ok(Layout::VersesFirst),
+        "chorus-first" => Ok(Layout::ChorusFirst),
+        _ => Err("expected verses-first or chorus-first".to_owned()),
+    }
+}
+
+fn parse_pointer_type(s: &str) -> Result<PointerType, String> {
+    match s {
+        "index" => Ok(PointerType::Index),
+        "cursor" => Ok(PointerType::Cursor),
+        _ => Err("expected index or cursor".to_owned()),
+    }
+}
+
+fn parse_tape_preallocate(s: &str) -> Result<TapePreallocate, String> {
+    match s {
+        "auto" => Ok(TapePreallocate::Auto),
+        _ => s
+            .parse::<usize>()
+            .map(TapePreallocate::Cells)
+            .map_err(|_| "expected a number of cells or auto".to_owned()),
+    }
+}
+
+fn parse_tape_init(s: &str) -> Result<TapeInit, String> {
+    if !s.chars().all(|c| c.is_ascii_hexdigit()) || !s.len().is_multiple_of(2) {
+        return Err("expected an even number of hexadecimal digits".to_owned());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|err| err.to_string()))
+        .collect::<Result<_, _>>()
+        .map(TapeInit)
+}
+
+fn parse_color(s: &str) -> Result<ColorChoice, String> {
+    match s {
+        "always" => Ok(ColorChoice::Always),
+        "auto" => Ok(ColorChoice::Auto),
+        "never" => Ok(ColorChoice::Never),
+        _ => Err("expected always, auto or never".to_owned()),
+    }
+}
+
+fn parse_dialect(s: &str) -> Result<BfDialect, String> {
+    match s {
+        "standard" => Ok(BfDialect::Standard),
+        "ook" => Ok(BfDialect::Ook),
+        "brainfuck2" => Ok(BfDialect::Brainfuck2),
+        "whitespace" => Ok(BfDialect::Whitespace),
+        _ => {
+            Err("unsupported dialect, expected standard, ook, brainfuck2 or whitespace".to_owned())
+        }
+    }
+}
+
+fn parse_extension(s: &str) -> Result<Extension, String> {
+    match s {
+        "sleep" => Ok(Extension::Sleep),
+        _ => Err("expected sleep".to_owned()),
+    }
+}
+
+fn parse_sleep_char(s: &str) -> Result<char, String> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if "+-<>[].,;".contains(c) => {
+            Err(format!("{:?} already has a meaning in Brainfuck", c))
+        }
+        (Some(c), None) => Ok(c),
+        _ => Err("expected a single character".to_owned()),
+    }
+}
+
+fn parse_comment_char(s: &str) -> Result<char, String> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if "+-<>[].,".contains(c) => {
+            Err(format!("{:?} already has a meaning in Brainfuck", c))
+        }
+        (Some(c), None) => Ok(c),
+        _ => Err("expected a single character".to_owned()),
+    }
+}
+
+fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("expected at least 1 line".to_owned()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_encoding(s: &str) -> Result<OutputEncoding, String> {
+    match s {
+        "utf8" | "utf-8" => Ok(OutputEncoding::Utf8),
+        "latin1" | "latin-1" => Ok(OutputEncoding::Latin1),
+        _ => Err("expected utf8 or latin1".to_owned()),
+    }
+}
+
+fn is_terminal(path: &Path) -> bool {
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .is_ok_and(|file| file.is_terminal())
+}
+
+fn print_version_json() {
+    let capabilities: Vec<String> = CAPABILITIES.iter().map(|c| format!("\"{}\"", c)).collect();
+    println!(
+        "{{\"name\":\"{}\",\"version\":\"{}\",\"commit\":\"{}\",\"capabilities\":[{}]}}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        option_env!("BRICKROLL_GIT_SHA").unwrap_or("unknown"),
+        capabilities.join(",")
+    );
+}
+
+fn print_passes() {
+    let width = PassKind::ALL
+        .iter()
+        .map(|p| p.name().len())
+        .max()
+        .unwrap_or(0);
+    for pass in PassKind::ALL {
+        let note = if pass.preserves_semantics() {
+            ""
+        } else {
+            " (may change behavior)"
+        };
+        println!("{:width$}  {}{}", pass.name(), pass.description(), note);
+    }
+}
+
+fn expand_glob(pattern: &str) -> Result<Vec<String>, String> {
+    let paths = glob::glob(pattern).map_err(|e| format!("Invalid glob \"{}\": {}", pattern, e))?;
+    let files: Vec<String> = paths
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file())
+        .map(|path| path.display().to_string())
+        .collect();
+    if files.is_empty() {
+        return Err(format!("No files match \"{}\"", pattern));
+    }
+    Ok(files)
+}
+
+/// Pairs each input file with `dir/<name>.<extension>`, rejecting inputs
+/// whose outputs would overwrite each other.
+fn out_dir_jobs(
+    files: &[String],
+    dir: &Path,
+    extension: &str,
+) -> Result<Vec<(String, PathBuf)>, String> {
+    let mut jobs = Vec::with_capacity(files.len());
+    let mut seen: HashMap<PathBuf, &str> = HashMap::new();
+    for file in files {
+        let name = Path::new(file).with_extension(extension);
+        let output = dir.join(name.file_name().unwrap_or_default());
+        if let Some(prev) = seen.insert(output.clone(), file) {
+            return Err(format!(
+                "\"{}\" and \"{}\" would both be written to \"{}\"",
+                prev,
+                file,
+                output.display()
+            ));
+        }
+        jobs.push((file.clone(), output));
+    }
+    Ok(jobs)
+}
+
+/// Pairs each input file with the same path with its extension replaced
+/// by `extension`, rejecting inputs that already have that extension
+fn default_jobs(files: &[String], extension: &str) -> Result<Vec<(String, PathBuf)>, String> {
+    files
+        .iter()
+        .map(|file| {
+            let output = Path::new(file).with_extension(extension);
+            if output == Path::new(file) {
+                return Err(format!(
+                    "\"{}\" would be overwritten by its own output, use -o to name the output",
+                    file
+                ));
+            }
+            Ok((file.clone(), output))
+        })
+        .collect()
+}
 
-    /// Name of input Brainfuck file
-    #[arg()]
-    file: String,
+/// Whether `output` was modified after `input`. Missing files or
+/// modification times count as out of date.
+fn is_up_to_date(input: &str, output: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified());
+    match (modified(Path::new(input)), modified(output)) {
+        (Ok(input), Ok(output)) => output > input,
+        _ => false,
+    }
+}
+
+/// The contents of the fenced code blocks in a Markdown document whose info
+/// string starts with `lang`, joined in order
+fn markdown_code_blocks(markdown: &str, lang: &str) -> String {
+    let mut res = String::new();
+    // whether we are in a code block, and if so whether it is in `lang`
+    let mut block: Option<bool> = None;
+    for line in markdown.lines() {
+        match (line.trim_start().strip_prefix("```"), block) {
+            (Some(info), None) => {
+                block = Some(info.split_whitespace().next() == Some(lang));
+            }
+            (Some(_), Some(_)) => block = None,
+            (None, Some(true)) => {
+                res.push_str(line);
+                res.push('\n');
+            }
+            (None, _) => {}
+        }
+    }
+    res
+}
+
+/// Displays a compiler error as the message printed after `error: `
+struct ErrorMessage<'a>(&'a CompilerError);
+
+impl fmt::Display for ErrorMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            CompilerError::FormatError(err) => write!(f, "could not format the output: {}", err),
+            CompilerError::UnbalancedBrackets { position } => {
+                write!(f, "unbalanced bracket at position {}", position)
+            }
+            CompilerError::NegativeLiteral => {
+                write!(f, "a negative number was assigned to an index variable")
+            }
+            CompilerError::UnprintableLiteral => write!(
+                f,
+                "a character or string literal holds a control character other than a newline"
+            ),
+            CompilerError::Io(err) => write!(f, "{}", err),
+            CompilerError::InvalidBase64 => write!(f, "the input is not base64-encoded UTF-8 text"),
+            CompilerError::EmptyProgram => write!(
+                f,
+                "the source contains no Brainfuck instructions (pass --allow-empty to compile it anyway)"
+            ),
+            CompilerError::ExecutionError {
+                position: Some(position),
+                message,
+            } => write!(f, "instruction {}: {}", position, message),
+            CompilerError::ExecutionError {
+                position: None,
+                message,
+            } => write!(f, "{}", message),
+            _ => write!(f, "unexpected error"),
+        }
+    }
+}
+
+fn compile_file(
+    file: &str,
+    output: &Path,
+    opts: &CompilerOptions,
+    file_opts: FileOptions,
+) -> Result<(), String> {
+    let bf = fs::read_to_string(file).map_err(|_| format!("Unable to read file \"{}\"", file))?;
+    let bf = if file_opts.base64 {
+        compiler::decode_base64(&bf)
+            .map_err(|err| format!("{}: error: {}", file, ErrorMessage(&err)))?
+    } else {
+        bf
+    };
+    let bf = if file_opts.from_markdown {
+        markdown_code_blocks(&bf, "brainfuck")
+    } else {
+        bf
+    };
+    #[cfg(feature = "hash")]
+    if file_opts.print_hash {
+        let hash = Compiler::read(&bf, opts)
+            .map_err(|err| format!("{}: error: {}", file, ErrorMessage(&err)))?
+            .input_hash();
+        let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        eprintln!("{}: {}", file, hex);
+    }
+    if file_opts.print_stats {
+        let stats = Compiler::from_optimized(&bf, opts)
+            .map_err(|err| format!("{}: error: {}", file, ErrorMessage(&err)))?
+            .stats();
+        let iterations = stats
+            .loop_iterations_bound
+            .map_or_else(|| "unknown".to_owned(), |n| n.to_string());
+        eprintln!(
+            "{}: {} tape reads, {} tape writes, {} loop iterations, {} verses, complexity {}",
+            file,
+            stats.tape_reads,
+            stats.tape_writes,
+            iterations,
+            stats.unique_functions,
+            stats.complexity
+        );
+    }
+    if file_opts.warn || file_opts.abort_on_warning {
+        let compiler = Compiler::read(&bf, opts)
+            .map_err(|err| format!("{}: error: {}", file, ErrorMessage(&err)))?;
+        for warning in compiler.warnings() {
+            match warning.position {
+                Some(position) => {
+                    eprintln!(
+                        "{}: warning: instruction {}: {}",
+                        file, position, warning.kind
+                    )
+                }
+                None => eprintln!("{}: warning: {}", file, warning.kind),
+            }
+        }
+        if file_opts.abort_on_warning && !compiler.warnings().is_empty() {
+            return Err(format!(
+                "{}: error: aborting because of warnings (--abort-on-warning)",
+                file
+            ));
+        }
+    }
+    if let Some(interp) = file_opts.compat_check {
+        let compiler = Compiler::from_optimized(&bf, opts)
+            .map_err(|err| format!("{}: error: {}", file, ErrorMessage(&err)))?;
+        let missing = interp.missing(compiler.required_features());
+        if !missing.is_empty() {
+            let missing: Vec<String> = missing.iter().map(|f| f.to_string()).collect();
+            return Err(format!(
+                "{}: error: the program needs {}, which {} ({}) does not support",
+                file,
+                missing.join(", "),
+                interp.name,
+                interp.description
+            ));
+        }
+    }
+    if file_opts.report_optimization {
+        let report = Compiler::output_diff_from_unoptimized(&bf, opts)
+            .map_err(|err| format!("{}: error: {}", file, ErrorMessage(&err)))?;
+        eprintln!(
+            "{}: {} -> {} lines ({:.1}% smaller)",
+            file, report.unoptimized_lines, report.optimized_lines, report.reduction_percent
+        );
+        for note in &report.optimization_notes {
+            eprintln!("  {}", note);
+        }
+    }
+    let emit = file_opts.emit;
+    if let Emit::Chunked(chunk_size) = emit {
+        let chunks = Compiler::from_optimized(&bf, opts)
+            .and_then(|compiler| compiler.chunk_output(chunk_size, opts))
+            .map_err(|err| format!("{}: error: {}", file, ErrorMessage(&err)))?;
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let output = chunk_path(output, i + 1);
+            write_output(file, &output, chunk.into_bytes(), file_opts)?;
+        }
+        return Ok(());
+    }
+    let res = match emit {
+        #[cfg(feature = "compression")]
+        Emit::Gzip => {
+            Compiler::from_optimized(&bf, opts).and_then(|compiler| compiler.compress_output(opts))
+        }
+        _ => text_output(&bf, output, opts, emit).map(String::into_bytes),
+    };
+    let res = res.map_err(|err| format!("{}: error: {}", file, ErrorMessage(&err)))?;
+    write_output(file, output, res, file_opts)
+}
+
+/// Writes the output for `file`, encoded as `file_opts` asks
+fn write_output(
+    file: &str,
+    output: &Path,
+    res: Vec<u8>,
+    file_opts: FileOptions,
+) -> Result<(), String> {
+    let res = match (file_opts.emit, file_opts.encoding) {
+        #[cfg(feature = "compression")]
+        (Emit::Gzip, _) => res,
+        (_, OutputEncoding::Utf8) => res,
+        (_, OutputEncoding::Latin1) => encode_latin1(&res).map_err(|c| {
+            format!(
+                "{}: error: output contains {:?}, which cannot be encoded as Latin-1",
+                file, c
+            )
+        })?,
+    };
+    fs::write(output, res).map_err(|_| format!("Unable to write to file \"{}\"", output.display()))
+}
+
+/// `output` with the number of a chunk inserted before its extension, as in
+/// `out.0001.rickroll`
+fn chunk_path(output: &Path, n: usize) -> PathBuf {
+    match output.extension() {
+        Some(extension) => {
+            output.with_extension(format!("{:04}.{}", n, extension.to_string_lossy()))
+        }
+        None => output.with_extension(format!("{:04}", n)),
+    }
+}
+
+/// Re-encodes UTF-8 text as Latin-1, or returns the first character that
+/// Latin-1 cannot represent
+fn encode_latin1(utf8: &[u8]) -> Result<Vec<u8>, char> {
+    String::from_utf8_lossy(utf8)
+        .chars()
+        .map(|c| u8::try_from(c).map_err(|_| c))
+        .collect()
+}
+
+/// The output for `emit` before any compression
+fn text_output(
+    bf: &str,
+    output: &Path,
+    opts: &CompilerOptions,
+    emit: Emit,
+) -> Result<String, CompilerError> {
+    match emit {
+        Emit::Rickroll(ColorChoice::Always) => Compiler::from_optimized(bf, opts)
+            .and_then(|compiler| compiler.output_with_syntax_highlighting(opts)),
+        Emit::Rickroll(ColorChoice::Auto) if is_terminal(output) => {
+            Compiler::from_optimized(bf, opts)
+                .and_then(|compiler| compiler.output_with_syntax_highlighting(opts))
+        }
+        Emit::Rickroll(_) => Compiler::compile_str(bf, opts),
+        Emit::NumberedRickroll => Compiler::from_optimized(bf, opts)
+            .and_then(|compiler| compiler.output_with_line_numbers(opts)),
+        Emit::StatsRickroll => Compiler::from_optimized(bf, opts)
+            .and_then(|compiler| compiler.output_with_statistics_header(opts)),
+        Emit::LibraryShim => {
+            Compiler::from_optimized(bf, opts).and_then(|compiler| compiler.as_library_shim(opts))
+        }
+        Emit::Bf => Compiler::optimized_instructions(bf, opts)
+            .map(|instrs| instrs.iter().map(|i| i.to_string()).collect::<String>() + "\n"),
+        Emit::PrettyIr => Compiler::from_optimized(bf, opts).map(|c| c.pretty_print_ir()),
+        Emit::Dot => Compiler::optimized_instructions(bf, opts)
+            .and_then(|instrs| dot::control_flow_graph(&instrs)),
+        Emit::Llvm => Compiler::optimized_instructions(bf, opts)
+            .and_then(|instrs| LlvmEmitter::new().emit(&instrs)),
+        Emit::Chunked(_) => unreachable!("chunked output is written by compile_file"),
+        #[cfg(feature = "compression")]
+        Emit::Gzip => Compiler::compile_str(bf, opts),
+    }
+}
+
+/// Compiles each `(input, output)` pair on a pool of `threads` workers and
+/// returns the number of files that failed. Errors are reported in the order
+/// of `jobs` once every file is done, so that the messages do not depend on
+/// how the work was scheduled.
+fn compile_all(
+    jobs: &[(String, PathBuf)],
+    opts: &CompilerOptions,
+    file_opts: FileOptions,
+    threads: usize,
+) -> usize {
+    let next = AtomicUsize::new(0);
+    let errors = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        for _ in 0..threads.clamp(1, jobs.len().max(1)) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((file, output)) = jobs.get(i) else {
+                    break;
+                };
+                if let Err(msg) = compile_file(file, output, opts, file_opts) {
+                    errors.lock().expect("a worker panicked").push((i, msg));
+                }
+            });
+        }
+    });
+    let mut errors = errors.into_inner().expect("a worker panicked");
+    errors.sort_unstable_by_key(|(i, _)| *i);
+    for (_, msg) in &errors {
+        eprintln!("{}", msg);
+    }
+    errors.len()
 }
 
 fn main() {
+    env_logger::init();
     let args = Args::parse();
-    if let Ok(bf) = fs::read_to_string(&args.file) {
-        let compiler = Compiler::read(&bf);
-        match compiler.output(args.indent, args.trace) {
-            Ok(output) => {
-                if let Err(_) = fs::write(&args.output, output) {
-                    eprintln!("Unable to write to file \"{}\"", args.output);
-                }
+    if args.version_json {
+        print_version_json();
+        return;
+    }
+    if args.list_passes {
+        print_passes();
+        return;
+    }
+    let sleep_char = args
+        .extension
+        .contains(&Extension::Sleep)
+        .then(|| args.sleep_char.unwrap_or('*'));
+    if args.comment_char.is_some() && args.comment_char == sleep_char {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "the comment character must differ from the sleep character",
+            )
+            .exit();
+    }
+    let mut files = args.files;
+    if let Some(pattern) = &args.glob {
+        match expand_glob(pattern) {
+            Ok(matches) => files.extend(matches),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                process::exit(1);
             }
-            Err(err) => eprintln!("error: {:?}", err),
         }
+    }
+    let extension = match args.target {
+        Target::Rickroll(_) => "rickroll",
+        Target::Llvm => "ll",
+    };
+    let jobs: Vec<(String, PathBuf)> = match (args.output, &args.out_dir) {
+        (Some(output), _) => {
+            if files.len() > 1 {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "-o can only be used with a single input file, use --out-dir instead",
+                    )
+                    .exit();
+            }
+            vec![(files[0].clone(), output)]
+        }
+        (None, Some(dir)) => out_dir_jobs(&files, dir, extension).unwrap_or_else(|msg| {
+            eprintln!("{}", msg);
+            process::exit(1);
+        }),
+        (None, None) => {
+            let extension = match args.target {
+                Target::Rickroll(_) => "rick",
+                Target::Llvm => "ll",
+            };
+            default_jobs(&files, extension).unwrap_or_else(|msg| {
+                eprintln!("{}", msg);
+                process::exit(1);
+            })
+        }
+    };
+    #[cfg(feature = "compression")]
+    let jobs: Vec<(String, PathBuf)> = if args.gzip {
+        jobs.into_iter()
+            .map(|(file, mut output)| {
+                output.as_mut_os_string().push(".gz");
+                (file, output)
+            })
+            .collect()
+    } else {
+        jobs
+    };
+    let jobs: Vec<(String, PathBuf)> = if args.incremental {
+        jobs.into_iter()
+            .filter(|(file, output)| {
+                let up_to_date = is_up_to_date(file, output);
+                if up_to_date {
+                    eprintln!("{}: up to date", file);
+                }
+                !up_to_date
+            })
+            .collect()
+    } else {
+        jobs
+    };
+    let threads = args.threads.unwrap_or_else(|| {
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    let opts = CompilerOptions {
+        indent: args.indent,
+        trace: args.trace,
+        preamble: !args.no_preamble,
+        chorus_label: args.chorus_name,
+        embed_source: args.embed_source,
+        explain: args.explain,
+        opt_level: args.opt_level,
+        passes: args.passes,
+        range: args.range,
+        target: match args.target {
+            Target::Rickroll(version) => version,
+            Target::Llvm => RickrollVersion::V1,
+        },
+        layout: args.layout,
+        debug_print_on_loop: args.debug_print_on_loop,
+        tape_preallocate: args.tape_preallocate,
+        seed_input: args.seed_input,
+        input_from_arg: args.input_from_arg,
+        split_on_bang: args.split_on_bang,
+        assertions: args.assertions,
+        pointer_start: args.pointer_start,
+        check_bounds: args.check_bounds,
+        sleep_char,
+        comment_char: args.comment_char,
+        allow_empty: args.allow_empty,
+        outline_loops: args.outline_loops,
+        dialect: args.bf_dialect,
+        tape_init: args.tape_init.map_or_else(Vec::new, |init| init.0),
+        pointer_type: args.pointer_type,
+        array_search: args.array_search,
+        max_chorus_lines: args.max_chorus_lines,
+        wrapping: args.wrapping,
+        no_wrap_check: args.no_wrap_check,
+    };
+    let emit = if args.emit_bf {
+        Emit::Bf
+    } else if args.pretty_ir {
+        Emit::PrettyIr
+    } else if args.emit_dot {
+        Emit::Dot
+    } else if let Target::Llvm = args.target {
+        Emit::Llvm
+    } else if args.line_numbers {
+        Emit::NumberedRickroll
+    } else if args.stats_header {
+        Emit::StatsRickroll
+    } else if args.library_shim {
+        Emit::LibraryShim
+    } else if let Some(chunk_size) = args.chunk_size {
+        Emit::Chunked(chunk_size)
     } else {
-        eprintln!("Unable to read file \"{}\"", args.file);
+        Emit::Rickroll(args.color)
+    };
+    #[cfg(feature = "compression")]
+    let emit = if args.gzip { Emit::Gzip } else { emit };
+    let file_opts = FileOptions {
+        emit,
+        encoding: args.output_encoding,
+        base64: args.base64,
+        from_markdown: args.from_markdown,
+        report_optimization: args.report_optimization,
+        warn: args.warn,
+        abort_on_warning: args.abort_on_warning,
+        print_stats: args.print_stats,
+        compat_check: args.compat_check,
+        #[cfg(feature = "hash")]
+        print_hash: args.print_hash,
+    };
+    if compile_all(&jobs, &opts, file_opts, threads) > 0 {
         process::exit(1);
     }
 }
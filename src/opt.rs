@@ -0,0 +1,473 @@
+use crate::bf::BfInstruction;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PassKind {
+    /// Replaces `[-]` with a single store of zero
+    ClearLoop,
+    /// Merges runs of `+` and `-` into one addition
+    CoalesceAdd,
+    /// Removes loops that start on a cell known to be zero
+    DeadLoop,
+    /// Removes stores of zero to a cell that is already zero
+    RedundantClear,
+}
+
+impl PassKind {
+    /// Every pass, in the order `-O` levels run them
+    pub const ALL: &'static [PassKind] = &[
+        Self::ClearLoop,
+        Self::CoalesceAdd,
+        Self::RedundantClear,
+        Self::DeadLoop,
+    ];
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::ClearLoop => "clear-loop",
+            Self::CoalesceAdd => "coalesce-add",
+            Self::DeadLoop => "dead-loop",
+            Self::RedundantClear => "redundant-clear",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<PassKind> {
+        Self::ALL.iter().copied().find(|pass| pass.name() == name)
+    }
+    pub fn description(&self) -> &str {
+        match self {
+            Self::ClearLoop => "replaces [-] with a single store of zero",
+            Self::CoalesceAdd => "merges runs of + and - into one addition",
+            Self::DeadLoop => "removes loops that start on a cell known to be zero",
+            Self::RedundantClear => {
+                "removes stores of zero to a cell that is already zero, as in [-][-]"
+            }
+        }
+    }
+    /// Whether the optimized program always behaves like the original one.
+    /// Cells are unbounded, so `[-]` never terminates on a negative cell,
+    /// while its replacement does.
+    pub fn preserves_semantics(&self) -> bool {
+        match self {
+            Self::ClearLoop => false,
+            Self::CoalesceAdd | Self::DeadLoop | Self::RedundantClear => true,
+        }
+    }
+    /// Whether the pass relies on every cell being zero when the program
+    /// starts
+    pub fn assumes_zero_tape(&self) -> bool {
+        match self {
+            Self::DeadLoop | Self::RedundantClear => true,
+            Self::ClearLoop | Self::CoalesceAdd => false,
+        }
+    }
+    pub fn run(&self, program: &[BfInstruction]) -> Vec<BfInstruction> {
+        let origins: Vec<usize> = (0..program.len()).collect();
+        self.run_with_origins(program, &origins).0
+    }
+    /// `run`, which also maps the origin of each instruction (such as its
+    /// position in the source program) to the instructions it became. An
+    /// instruction that replaces several takes the origin of the first.
+    pub(crate) fn run_with_origins(
+        &self,
+        program: &[BfInstruction],
+        origins: &[usize],
+    ) -> (Vec<BfInstruction>, Vec<usize>) {
+        match self {
+            Self::ClearLoop => clear_loop(program, origins),
+            Self::CoalesceAdd => coalesce_add(program, origins),
+            Self::DeadLoop => dead_loop(program, origins),
+            Self::RedundantClear => redundant_clear(program, origins),
+        }
+    }
+}
+
+pub struct PassPipeline {
+    passes: Vec<PassKind>,
+}
+
+impl PassPipeline {
+    pub fn new(passes: Vec<PassKind>) -> PassPipeline {
+        Self { passes }
+    }
+    pub fn for_level(level: u8) -> PassPipeline {
+        match level {
+            0 => Self::new(Vec::new()),
+            1 => Self::new(vec![
+                PassKind::ClearLoop,
+                PassKind::CoalesceAdd,
+                PassKind::RedundantClear,
+            ]),
+            _ => Self::new(PassKind::ALL.to_vec()),
+        }
+    }
+    pub fn passes(&self) -> &[PassKind] {
+        &self.passes
+    }
+    pub fn run(&self, program: &mut Vec<BfInstruction>) {
+        let mut origins = (0..program.len()).collect();
+        self.run_with_origins(program, &mut origins);
+    }
+    /// `run`, which keeps `origins` in step with the program, see
+    /// `PassKind::run_with_origins`
+    pub(crate) fn run_with_origins(
+        &self,
+        program: &mut Vec<BfInstruction>,
+        origins: &mut Vec<usize>,
+    ) {
+        for pass in &self.passes {
+            let before = program.len();
+            (*program, *origins) = pass.run_with_origins(program, origins);
+            log::debug!(
+                "{}: {} -> {} instructions",
+                pass.name(),
+                before,
+                program.len()
+            );
+        }
+    }
+}
+
+/// The value of the current cell before each instruction runs, where it is
+/// known at compile time. Nothing is known inside loops, and after a loop
+/// only the current cell (which must be zero) is.
+pub fn current_cell_values(program: &[BfInstruction]) -> Vec<Option<i64>> {
+    // cells missing from the map are zero until the first loop and unknown
+    // afterwards
+    let mut cells: HashMap<i64, Option<i64>> = HashMap::new();
+    let mut fresh = true;
+    let mut pos = 0i64;
+    let mut res = Vec::with_capacity(program.len());
+    for instr in program {
+        let current = cells.get(&pos).copied().unwrap_or(fresh.then_some(0));
+        res.push(current);
+        match instr {
+            BfInstruction::IncPointer => pos += 1,
+            BfInstruction::DecPointer => pos -= 1,
+            BfInstruction::IncData => {
+                cells.insert(pos, current.and_then(|v| v.checked_add(1)));
+            }
+            BfInstruction::DecData => {
+                cells.insert(pos, current.and_then(|v| v.checked_sub(1)));
+            }
+            BfInstruction::AddData(n) => {
+                cells.insert(pos, current.and_then(|v| v.checked_add(*n as i64)));
+            }
+            BfInstruction::ClearData => {
+                cells.insert(pos, Some(0));
+            }
+            BfInstruction::Input => {
+                cells.insert(pos, None);
+            }
+            BfInstruction::Output | BfInstruction::AssertData(_) | BfInstruction::Sleep => {}
+            BfInstruction::LoopStart => {
+                cells.clear();
+                fresh = false;
+            }
+            BfInstruction::LoopEnd => {
+                cells.clear();
+                fresh = false;
+                cells.insert(pos, Some(0));
+            }
+        }
+    }
+    res
+}
+
+/// The number of times each loop runs, indexed by its `LoopStart`, where it
+/// is known at compile time: the value of its cell must be known when the
+/// loop is reached, and its body must only move back to where it started
+/// and change that cell by a fixed amount (or clear it) each iteration, see
+/// `current_cell_values`. Only loops outside of other loops can be known.
+pub fn loop_iterations(program: &[BfInstruction]) -> Vec<Option<u64>> {
+    let known = current_cell_values(program);
+    let mut res = vec![None; program.len()];
+    let mut starts = Vec::new();
+    for (i, instr) in program.iter().enumerate() {
+        match instr {
+            BfInstruction::LoopStart => starts.push(i),
+            BfInstruction::LoopEnd => {
+                if let Some(start) = starts.pop() {
+                    res[start] = known[start]
+                        .and_then(|value| simple_loop_iterations(&program[start + 1..i], value));
+                }
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+/// Number of times a loop whose current cell starts at `start` runs, if its
+/// body only moves back to where it started and changes that cell by a
+/// fixed amount (or clears it) each iteration.
+fn simple_loop_iterations(body: &[BfInstruction], start: i64) -> Option<u64> {
+    let mut offset = 0i64;
+    let mut delta = 0i64;
+    let mut clears = false;
+    for instr in body {
+        match instr {
+            BfInstruction::IncPointer => offset += 1,
+            BfInstruction::DecPointer => offset -= 1,
+            BfInstruction::IncData if offset == 0 => delta += 1,
+            BfInstruction::DecData if offset == 0 => delta -= 1,
+            BfInstruction::AddData(n) if offset == 0 => delta += *n as i64,
+            BfInstruction::ClearData if offset == 0 => clears = true,
+            BfInstruction::IncData
+            | BfInstruction::DecData
+            | BfInstruction::AddData(_)
+            | BfInstruction::ClearData
+            | BfInstruction::Output
+            | BfInstruction::AssertData(_)
+            | BfInstruction::Sleep => {}
+            BfInstruction::Input | BfInstruction::LoopStart | BfInstruction::LoopEnd => {
+                return None
+            }
+        }
+    }
+    if offset != 0 || (clears && delta != 0) {
+        None
+    } else if start == 0 {
+        Some(0)
+    } else if clears {
+        Some(1)
+    } else if delta != 0 && start % delta == 0 && start / delta < 0 {
+        Some(start.unsigned_abs() / delta.unsigned_abs())
+    } else {
+        None
+    }
+}
+
+/// Whether each `LoopStart` opens a loop that leaves the pointer where it
+/// found it, as do all loops nested in it
+pub(crate) fn balanced_loops(program: &[BfInstruction]) -> Vec<bool> {
+    let mut res = vec![false; program.len()];
+    // start, net pointer movement and whether all nested loops are balanced
+    let mut open: Vec<(usize, i64, bool)> = Vec::new();
+    for (i, instr) in program.iter().enumerate() {
+        match instr {
+            BfInstruction::LoopStart => open.push((i, 0, true)),
+            BfInstruction::LoopEnd => {
+                if let Some((start, moved, nested)) = open.pop() {
+                    res[start] = moved == 0 && nested;
+                    if let Some(parent) = open.last_mut() {
+                        parent.2 &= res[start];
+                    }
+                }
+            }
+            BfInstruction::IncPointer => {
+                if let Some(innermost) = open.last_mut() {
+                    innermost.1 += 1;
+                }
+            }
+            BfInstruction::DecPointer => {
+                if let Some(innermost) = open.last_mut() {
+                    innermost.1 -= 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+/// The position of the pointer before each instruction runs, where it is
+/// known at compile time. Loops that leave the pointer where they found it
+/// keep it known, while the first other loop makes it unknown for the rest
+/// of the program.
+pub(crate) fn pointer_positions(
+    program: &[BfInstruction],
+    pointer_start: usize,
+) -> Vec<Option<i64>> {
+    let balanced = balanced_loops(program);
+    let mut pos = Some(pointer_start as i64);
+    let mut res = Vec::with_capacity(program.len());
+    for (i, instr) in program.iter().enumerate() {
+        res.push(pos);
+        match instr {
+            BfInstruction::IncPointer => pos = pos.map(|p| p + 1),
+            BfInstruction::DecPointer => pos = pos.map(|p| p - 1),
+            BfInstruction::LoopStart if !balanced[i] => pos = None,
+            _ => {}
+        }
+    }
+    res
+}
+
+/// Tape cells that hold the same value whenever the program looks at them
+/// (by printing them, testing them in a loop condition or asserting on
+/// them), with that value. A cell qualifies if it is only modified outside
+/// of loops, never read into, and not looked at before its last
+/// modification. Once a loop moves the pointer by a varying amount, later
+/// accesses can hit any cell, so cells looked at afterwards must already be
+/// final and a modification there means no cell is known to be constant.
+pub fn constant_cells(program: &[BfInstruction], pointer_start: usize) -> HashMap<usize, u8> {
+    let positions = pointer_positions(program, pointer_start);
+    let mut depth = 0usize;
+    // values of cells modified outside of loops
+    let mut values: HashMap<i64, i64> = HashMap::new();
+    let mut observed = HashSet::new();
+    let mut observed_all = false;
+    let mut modified = HashSet::new();
+    for (instr, pos) in program.iter().zip(positions) {
+        let delta = match instr {
+            BfInstruction::IncPointer | BfInstruction::DecPointer => continue,
+            BfInstruction::LoopStart
+            | BfInstruction::LoopEnd
+            | BfInstruction::Output
+            | BfInstruction::AssertData(_)
+            | BfInstruction::Sleep => {
+                match pos {
+                    Some(p) => {
+                        observed.insert(p);
+                    }
+                    None => observed_all = true,
+                }
+                match instr {
+                    BfInstruction::LoopStart => depth += 1,
+                    BfInstruction::LoopEnd => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                continue;
+            }
+            BfInstruction::IncData => Some(1),
+            BfInstruction::DecData => Some(-1),
+            BfInstruction::AddData(n) => Some(*n as i64),
+            BfInstruction::ClearData => None,
+            BfInstruction::Input => {
+                match pos {
+                    Some(p) => modified.insert(p),
+                    None => return HashMap::new(),
+                };
+                continue;
+            }
+        };
+        let Some(p) = pos else {
+            return HashMap::new();
+        };
+        if depth > 0 || observed_all || observed.contains(&p) {
+            modified.insert(p);
+        }
+        let value = values.entry(p).or_insert(0);
+        *value = match delta {
+            Some(delta) => value.saturating_add(delta),
+            None => 0,
+        };
+    }
+    values
+        .keys()
+        .chain(&observed)
+        .filter(|p| !modified.contains(p))
+        .filter_map(|p| {
+            let cell = usize::try_from(*p).ok()?;
+            let value = u8::try_from(values.get(p).copied().unwrap_or(0)).ok()?;
+            Some((cell, value))
+        })
+        .collect()
+}
+
+fn clear_loop(program: &[BfInstruction], origins: &[usize]) -> (Vec<BfInstruction>, Vec<usize>) {
+    let mut res = Vec::with_capacity(program.len());
+    let mut res_origins = Vec::with_capacity(program.len());
+    let mut i = 0;
+    while i < program.len() {
+        res_origins.push(origins[i]);
+        if let [BfInstruction::LoopStart, BfInstruction::DecData, BfInstruction::LoopEnd, ..] =
+            program[i..]
+        {
+            res.push(BfInstruction::ClearData);
+            i += 3;
+        } else {
+            res.push(program[i]);
+            i += 1;
+        }
+    }
+    (res, res_origins)
+}
+
+fn dead_loop(program: &[BfInstruction], origins: &[usize]) -> (Vec<BfInstruction>, Vec<usize>) {
+    // removing a loop keeps more cells known, which can make later loops dead
+    let mut res = program.to_vec();
+    let mut res_origins = origins.to_vec();
+    loop {
+        let known = current_cell_values(&res);
+        let mut next = Vec::with_capacity(res.len());
+        let mut next_origins = Vec::with_capacity(res.len());
+        let mut skip_depth = 0usize;
+        for ((instr, known), origin) in res.iter().zip(&known).zip(&res_origins) {
+            if skip_depth > 0 {
+                match instr {
+                    BfInstruction::LoopStart => skip_depth += 1,
+                    BfInstruction::LoopEnd => skip_depth -= 1,
+                    _ => {}
+                }
+            } else if *instr == BfInstruction::LoopStart && *known == Some(0) {
+                skip_depth = 1;
+            } else {
+                next.push(*instr);
+                next_origins.push(*origin);
+            }
+        }
+        if next.len() == res.len() {
+            return (res, res_origins);
+        }
+        log::trace!("dead-loop: removed {} instructions", res.len() - next.len());
+        res = next;
+        res_origins = next_origins;
+    }
+}
+
+fn redundant_clear(
+    program: &[BfInstruction],
+    origins: &[usize],
+) -> (Vec<BfInstruction>, Vec<usize>) {
+    let mut res = Vec::with_capacity(program.len());
+    let mut res_origins = Vec::with_capacity(program.len());
+    // the tape starts out zero, and a loop only ends on a zero cell
+    let mut zero = true;
+    for (instr, origin) in program.iter().zip(origins) {
+        match instr {
+            BfInstruction::ClearData if zero => continue,
+            BfInstruction::ClearData | BfInstruction::LoopEnd => zero = true,
+            BfInstruction::Output | BfInstruction::AssertData(_) | BfInstruction::Sleep => {}
+            _ => zero = false,
+        }
+        res.push(*instr);
+        res_origins.push(*origin);
+    }
+    (res, res_origins)
+}
+
+fn coalesce_add(program: &[BfInstruction], origins: &[usize]) -> (Vec<BfInstruction>, Vec<usize>) {
+    let mut res = Vec::with_capacity(program.len());
+    let mut res_origins = Vec::with_capacity(program.len());
+    let mut i = 0;
+    while i < program.len() {
+        let mut amount = 0i32;
+        let mut j = i;
+        while let Some(delta) = program.get(j).and_then(|instr| match instr {
+            BfInstruction::IncData => Some(1),
+            BfInstruction::DecData => Some(-1),
+            BfInstruction::AddData(n) => Some(*n),
+            _ => None,
+        }) {
+            // a run too long for one `AddData` continues in the next one
+            let Some(sum) = amount.checked_add(delta) else {
+                break;
+            };
+            amount = sum;
+            j += 1;
+        }
+        if j == i {
+            res.push(program[i]);
+            res_origins.push(origins[i]);
+            i += 1;
+        } else {
+            if amount != 0 {
+                res.push(BfInstruction::AddData(amount));
+                res_origins.push(origins[i]);
+            }
+            i = j;
+        }
+    }
+    (res, res_origins)
+}
@@ -0,0 +1,110 @@
+use crate::compiler::{Cmd, Expr, Function};
+use std::fmt::{self, Write};
+
+const INDENT: &str = "    ";
+
+fn args(f: &Function) -> String {
+    f.vars()
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn expr(e: &Expr) -> String {
+    match e {
+        Expr::ArrayAccess(array, idx) => format!("{}[{}]", array, idx),
+        _ => e.to_string(),
+    }
+}
+
+/// Whether each command is a `StartCond` that opens a loop (as opposed to an
+/// `if`), which is only known once its end is reached.
+fn loop_starts(cmds: &[Cmd]) -> Vec<bool> {
+    let mut is_loop = vec![false; cmds.len()];
+    let mut open = Vec::new();
+    for (i, cmd) in cmds.iter().enumerate() {
+        match cmd {
+            Cmd::StartCond(_) => open.push(i),
+            Cmd::EndWhile => {
+                if let Some(start) = open.pop() {
+                    is_loop[start] = true;
+                }
+            }
+            Cmd::EndIf => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+    is_loop
+}
+
+fn write_cmds(res: &mut String, cmds: &[Cmd]) -> fmt::Result {
+    let is_loop = loop_starts(cmds);
+    let mut level = 0usize;
+    let mut in_fn = false;
+    for (i, cmd) in cmds.iter().enumerate() {
+        if let Cmd::DeclareFn(_) | Cmd::DeclareChorus(..) = cmd {
+            if in_fn {
+                writeln!(res, "}}")?;
+                writeln!(res)?;
+            }
+            in_fn = true;
+            level = 0;
+        }
+        if let Cmd::EndIf | Cmd::EndWhile = cmd {
+            level = level.saturating_sub(1);
+        }
+        let depth = level
+            + usize::from(in_fn && !matches!(cmd, Cmd::DeclareFn(_) | Cmd::DeclareChorus(..)));
+        res.push_str(&INDENT.repeat(depth));
+        match cmd {
+            Cmd::DeclareVar(v) => writeln!(res, "let {};", v)?,
+            Cmd::DeclareFn(f) => writeln!(res, "fn {}({}) {{", f.name(), args(f))?,
+            Cmd::Return(e) => writeln!(res, "return {};", expr(e))?,
+            Cmd::DeclareChorus(label, param) => {
+                let param = param.map(|v| v.to_string()).unwrap_or_default();
+                match label {
+                    Some(label) => writeln!(res, "fn chorus({}) {{ // {}", param, label)?,
+                    None => writeln!(res, "fn chorus({}) {{", param)?,
+                }
+            }
+            Cmd::Assign(v, e) => writeln!(res, "{} = {};", v, expr(e))?,
+            Cmd::Call(f, v) => writeln!(res, "{} = {}({});", v, f.name(), args(f))?,
+            Cmd::CallNoReturn(f) => writeln!(res, "{}({});", f.name(), args(f))?,
+            Cmd::StartCond(e) => {
+                let keyword = if is_loop[i] { "while" } else { "if" };
+                writeln!(res, "{} ({}) {{", keyword, expr(e))?;
+                level += 1;
+            }
+            Cmd::EndIf | Cmd::EndWhile => writeln!(res, "}}")?,
+            Cmd::Halt => writeln!(res, "halt;")?,
+            Cmd::Trace(ln) => writeln!(res, "trace({});", ln)?,
+            Cmd::Comment(text) => {
+                write!(res, "// ")?;
+                for c in text.chars() {
+                    if c.is_control() {
+                        write!(res, "{}", c.escape_default())?;
+                    } else {
+                        write!(res, "{}", c)?;
+                    }
+                }
+                writeln!(res)?
+            }
+        }
+    }
+    if in_fn {
+        writeln!(res, "}}")?;
+    }
+    Ok(())
+}
+
+/// Renders commands as Rust-like pseudocode, one statement per line, for
+/// inspecting the generated program independently of Rickroll syntax.
+pub(crate) fn pretty_print_cmds(cmds: &[Cmd]) -> String {
+    let mut res = String::new();
+    // writing to a String cannot fail
+    write_cmds(&mut res, cmds).expect("formatting IR failed");
+    res
+}
@@ -0,0 +1,3 @@
+#!/usr/bin/env brickroll
+Three exclamation marks; assert cell == 0
++++++++[>+++++<-]>--...
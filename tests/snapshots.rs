@@ -0,0 +1,37 @@
+//! Compares the output for each program in `tests/fixtures` with its snapshot
+//! in `tests/snapshots`. Run with `UPDATE_SNAPSHOTS=1` to regenerate them.
+
+use brickroll::compiler::{Compiler, CompilerOptions};
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn fixtures_match_snapshots() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut fixtures: Vec<_> = fs::read_dir(dir.join("fixtures"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bf"))
+        .collect();
+    fixtures.sort();
+    assert!(!fixtures.is_empty());
+
+    let opts = CompilerOptions::default();
+    let mut mismatched = Vec::new();
+    for fixture in &fixtures {
+        let name = fixture.file_stem().unwrap().to_str().unwrap();
+        let snapshot = dir.join("snapshots").join(format!("{}.rickroll", name));
+        let compiler = Compiler::from_path(fixture, &opts).unwrap();
+        if !compiler
+            .assert_output_matches_snapshot(&snapshot, &opts)
+            .unwrap()
+        {
+            mismatched.push(name.to_owned());
+        }
+    }
+    assert!(
+        mismatched.is_empty(),
+        "output differs from the snapshots of {:?}",
+        mismatched
+    );
+}